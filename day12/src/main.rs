@@ -1,12 +1,140 @@
-use std::{
-    cmp::Ordering,
-    collections::{BinaryHeap, HashMap, HashSet},
-};
+use std::{cmp::Ordering, collections::BinaryHeap};
+
+#[derive(Debug, Clone, Copy)]
+enum StepCost {
+    // Every step costs 1, a plain BFS
+    Uniform,
+    // Costs base per step, plus per_climb/per_descent per unit of height gained/lost
+    HeightChange {
+        base: usize,
+        per_climb: usize,
+        per_descent: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct MovementRules {
+    max_climb: u8,
+    max_descent: u8,
+    diagonal: bool,
+    cost: StepCost,
+}
+impl Default for MovementRules {
+    // climb at most one step up, descend any amount
+    fn default() -> Self {
+        Self {
+            max_climb: 1,
+            max_descent: u8::MAX,
+            diagonal: false,
+            cost: StepCost::Uniform,
+        }
+    }
+}
+impl MovementRules {
+    fn allows(&self, src_height: u8, dest_height: u8) -> bool {
+        if dest_height >= src_height {
+            dest_height - src_height <= self.max_climb
+        } else {
+            src_height - dest_height <= self.max_descent
+        }
+    }
+
+    fn step_cost(&self, src_height: u8, dest_height: u8) -> usize {
+        match self.cost {
+            StepCost::Uniform => 1,
+            StepCost::HeightChange {
+                base,
+                per_climb,
+                per_descent,
+            } => {
+                let climb = dest_height.saturating_sub(src_height) as usize;
+                let descent = src_height.saturating_sub(dest_height) as usize;
+                base + climb * per_climb + descent * per_descent
+            }
+        }
+    }
+
+    fn offsets(&self) -> &'static [(isize, isize)] {
+        const ORTHOGONAL: [(isize, isize); 4] = [(0, 1), (0, -1), (-1, 0), (1, 0)];
+        const ALL: [(isize, isize); 8] = [
+            (0, 1),
+            (0, -1),
+            (-1, 0),
+            (1, 0),
+            (-1, -1),
+            (1, -1),
+            (-1, 1),
+            (1, 1),
+        ];
+
+        if self.diagonal {
+            &ALL
+        } else {
+            &ORTHOGONAL
+        }
+    }
+}
+
+// Cells are given as heights (a to z) or S/E for the start and goal
+#[derive(Debug, Clone)]
+struct Query {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Default)]
+struct Options {
+    rules: MovementRules,
+    queries: Vec<Query>,
+}
+impl Options {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Self {
+        let mut result = Self::default();
+        let mut per_climb = 0;
+        let mut per_descent = 0;
+
+        while let Some(arg) = args.next() {
+            let mut value = || -> String {
+                args.next()
+                    .unwrap_or_else(|| panic!("missing value for {}", arg))
+            };
+
+            match arg.as_str() {
+                "--max-climb" => result.rules.max_climb = value().parse().unwrap(),
+                "--max-descent" => result.rules.max_descent = value().parse().unwrap(),
+                "--diagonal" => result.rules.diagonal = true,
+                "--climb-cost" => per_climb = value().parse().unwrap(),
+                "--descent-cost" => per_descent = value().parse().unwrap(),
+                "--query" => {
+                    let value = value();
+                    let (from, to) = value
+                        .split_once(':')
+                        .unwrap_or_else(|| panic!("query {} is not of the form FROM:TO", value));
+                    result.queries.push(Query {
+                        from: from.to_string(),
+                        to: to.to_string(),
+                    });
+                }
+                _ => panic!("unknown argument {}", arg),
+            }
+        }
+
+        if per_climb != 0 || per_descent != 0 {
+            result.rules.cost = StepCost::HeightChange {
+                base: 1,
+                per_climb,
+                per_descent,
+            };
+        }
+
+        result
+    }
+}
 
 struct HeightMap {
     data: Vec<u8>,
     width: usize,
-    lowest_points: Vec<(usize, usize)>,
+    rules: MovementRules,
 }
 impl HeightMap {
     fn parse(str: &str) -> (Self, (usize, usize), (usize, usize)) {
@@ -15,7 +143,6 @@ impl HeightMap {
 
         let mut start = (0, 0);
         let mut goal = (0, 0);
-        let mut lowest_points = Vec::new();
 
         for (y, line) in str.lines().enumerate() {
             width = line.len();
@@ -31,10 +158,6 @@ impl HeightMap {
                     byte = b'z';
                 }
 
-                if byte == b'a' {
-                    lowest_points.push((x, y));
-                }
-
                 data.push(byte - b'a');
             }
         }
@@ -43,7 +166,7 @@ impl HeightMap {
             Self {
                 data,
                 width,
-                lowest_points,
+                rules: MovementRules::default(),
             },
             start,
             goal,
@@ -55,10 +178,10 @@ impl HeightMap {
     }
 
     fn val_at(&self, x: usize, y: usize) -> Option<u8> {
-        if x > self.width {
+        if x >= self.width {
             return None;
         }
-        if y > self.height() {
+        if y >= self.height() {
             return None;
         }
 
@@ -66,76 +189,87 @@ impl HeightMap {
         self.data.get(idx).copied()
     }
 
-    fn traversable(&self, cur_height: u8, dest_x: usize, dest_y: usize) -> bool {
-        if let Some(dest_height) = self.val_at(dest_x, dest_y) {
-            dest_height <= cur_height || dest_height == (cur_height + 1)
-        } else {
-            false
-        }
+    fn cells_with_height(&self, height: u8) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.data
+            .iter()
+            .enumerate()
+            .filter(move |(_, &h)| h == height)
+            .map(|(idx, _)| (idx % self.width, idx / self.width))
     }
 
-    fn possible_moves_from(&self, x: usize, y: usize) -> Vec<(usize, usize)> {
-        let Some(cur_height) = self.val_at(x, y) else {
-            return Vec::new();
-        };
-
+    // Resolves a query cell specifier into the set of cells it names
+    fn resolve_cells(
+        &self,
+        spec: &str,
+        start: (usize, usize),
+        goal: (usize, usize),
+    ) -> Vec<(usize, usize)> {
         let mut result = Vec::new();
-
-        // up
-        if self.traversable(cur_height, x, y.checked_add(1).unwrap_or(usize::MAX)) {
-            result.push((x, y + 1));
-        }
-
-        // down
-        if self.traversable(cur_height, x, y.checked_sub(1).unwrap_or(usize::MAX)) {
-            result.push((x, y - 1));
-        }
-
-        // left
-        if self.traversable(cur_height, x.checked_sub(1).unwrap_or(usize::MAX), y) {
-            result.push((x - 1, y));
-        }
-
-        // right
-        if self.traversable(cur_height, x.checked_add(1).unwrap_or(usize::MAX), y) {
-            result.push((x + 1, y));
+        for c in spec.bytes() {
+            match c {
+                b'S' => result.push(start),
+                b'E' => result.push(goal),
+                b'a'..=b'z' => result.extend(self.cells_with_height(c - b'a')),
+                _ => panic!("unknown cell specifier {}", c as char),
+            }
         }
 
+        result.sort_unstable();
+        result.dedup();
         result
     }
 
-    fn possible_moves_to(&self, x: usize, y: usize) -> Vec<(usize, usize)> {
-        let mut result = Vec::new();
+    fn neighbors(&self, x: usize, y: usize) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.rules.offsets().iter().filter_map(move |&(dx, dy)| {
+            let x = x.checked_add_signed(dx)?;
+            let y = y.checked_add_signed(dy)?;
+            self.val_at(x, y).map(|_| (x, y))
+        })
+    }
 
-        // up
-        if let Some(src_height) = self.val_at(x, y.checked_add(1).unwrap_or(usize::MAX)) {
-            if self.traversable(src_height, x, y) {
-                result.push((x, y + 1));
-            }
+    fn traversable(&self, cur_height: u8, dest_x: usize, dest_y: usize) -> bool {
+        if let Some(dest_height) = self.val_at(dest_x, dest_y) {
+            self.rules.allows(cur_height, dest_height)
+        } else {
+            false
         }
+    }
 
-        // down
-        if let Some(src_height) = self.val_at(x, y.checked_sub(1).unwrap_or(usize::MAX)) {
-            if self.traversable(src_height, x, y) {
-                result.push((x, y - 1));
-            }
-        }
+    // Returns every position reachable in one step from (x, y), with the step's cost
+    fn possible_moves_from(&self, x: usize, y: usize) -> Vec<((usize, usize), usize)> {
+        let Some(cur_height) = self.val_at(x, y) else {
+            return Vec::new();
+        };
 
-        // left
-        if let Some(src_height) = self.val_at(x.checked_sub(1).unwrap_or(usize::MAX), y) {
-            if self.traversable(src_height, x, y) {
-                result.push((x - 1, y));
-            }
-        }
+        self.neighbors(x, y)
+            .filter(|&(dest_x, dest_y)| self.traversable(cur_height, dest_x, dest_y))
+            .map(|(dest_x, dest_y)| {
+                let dest_height = self.val_at(dest_x, dest_y).unwrap();
+                (
+                    (dest_x, dest_y),
+                    self.rules.step_cost(cur_height, dest_height),
+                )
+            })
+            .collect()
+    }
 
-        // right
-        if let Some(src_height) = self.val_at(x.checked_add(1).unwrap_or(usize::MAX), y) {
-            if self.traversable(src_height, x, y) {
-                result.push((x + 1, y));
-            }
-        }
+    // Returns every position that can reach (x, y) in one step, with the step's cost
+    fn possible_moves_to(&self, x: usize, y: usize) -> Vec<((usize, usize), usize)> {
+        let Some(dest_height) = self.val_at(x, y) else {
+            return Vec::new();
+        };
 
-        result
+        self.neighbors(x, y)
+            .filter_map(|(src_x, src_y)| {
+                let src_height = self.val_at(src_x, src_y)?;
+                self.traversable(src_height, x, y).then(|| {
+                    (
+                        (src_x, src_y),
+                        self.rules.step_cost(src_height, dest_height),
+                    )
+                })
+            })
+            .collect()
     }
 }
 
@@ -164,72 +298,162 @@ impl Ord for Visit {
     }
 }
 
-fn len_of_shortest_path(start: (usize, usize), goals: &[(usize, usize)], map: &HeightMap, backwards: bool) -> usize {
-    let mut queue = BinaryHeap::new();
-    let mut distances = HashMap::new();
-    let mut visited = HashSet::new();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Route {
+    source: (usize, usize),
+    target: (usize, usize),
+    distance: usize,
+}
 
-    for y in 0..map.height() {
-        for x in 0..map.width {
-            distances.insert((x, y), usize::MAX);
+// Distance from the nearest origin to every cell, or to the nearest origin when backwards
+struct DistanceField {
+    width: usize,
+    backwards: bool,
+    distances: Vec<usize>,
+    origins: Vec<Option<(usize, usize)>>,
+}
+impl DistanceField {
+    fn compute(map: &HeightMap, origins: &[(usize, usize)], backwards: bool) -> Self {
+        let mut queue = BinaryHeap::new();
+        let mut distances = vec![usize::MAX; map.data.len()];
+        let mut nearest_origins = vec![None; map.data.len()];
+        let mut visited = vec![false; map.data.len()];
+
+        for &origin in origins {
+            let idx = origin.1 * map.width + origin.0;
+            distances[idx] = 0;
+            nearest_origins[idx] = Some(origin);
+            queue.push(Visit {
+                pos: origin,
+                distance: 0,
+            });
         }
-    }
-    distances.insert(start, 0);
-    queue.push(Visit {
-        pos: start,
-        distance: 0,
-    });
 
-    while let Some(Visit { pos, distance }) = queue.pop() {
-        if !visited.insert(pos) {
-            continue;
+        while let Some(Visit { pos, distance }) = queue.pop() {
+            let idx = pos.1 * map.width + pos.0;
+            if std::mem::replace(&mut visited[idx], true) {
+                continue;
+            }
+
+            let neighbors = if backwards {
+                map.possible_moves_to(pos.0, pos.1)
+            } else {
+                map.possible_moves_from(pos.0, pos.1)
+            };
+
+            for (neighbor, cost) in neighbors {
+                let new_distance = distance + cost;
+                let neighbor_idx = neighbor.1 * map.width + neighbor.0;
+                if distances[neighbor_idx] > new_distance {
+                    distances[neighbor_idx] = new_distance;
+                    nearest_origins[neighbor_idx] = nearest_origins[idx];
+                    queue.push(Visit {
+                        pos: neighbor,
+                        distance: new_distance,
+                    });
+                }
+            }
         }
 
-        let neighbors = if backwards {
-            map.possible_moves_to(pos.0, pos.1)
-        } else {
-            map.possible_moves_from(pos.0, pos.1)
-        };
+        Self {
+            width: map.width,
+            backwards,
+            distances,
+            origins: nearest_origins,
+        }
+    }
+
+    fn distance_to(&self, x: usize, y: usize) -> Option<usize> {
+        self.distances
+            .get(y * self.width + x)
+            .copied()
+            .filter(|&distance| distance != usize::MAX)
+    }
+
+    // Closest pair between the origins and any of cells
+    fn nearest(&self, cells: &[(usize, usize)]) -> Option<Route> {
+        cells
+            .iter()
+            .filter_map(|&(x, y)| {
+                let distance = self.distance_to(x, y)?;
+                let origin = self.origins[y * self.width + x].unwrap();
+                Some(if self.backwards {
+                    Route {
+                        source: (x, y),
+                        target: origin,
+                        distance,
+                    }
+                } else {
+                    Route {
+                        source: origin,
+                        target: (x, y),
+                        distance,
+                    }
+                })
+            })
+            .min_by_key(|route| (route.distance, route.source, route.target))
+    }
 
-        for neighbor in neighbors {
-            let new_distance = distance + 1;
-            if distances.get(&neighbor).copied().unwrap_or(usize::MAX) > new_distance {
-                distances.insert(neighbor, new_distance);
-                queue.push(Visit {
-                    pos: neighbor,
-                    distance: new_distance,
-                });
+    #[allow(dead_code)]
+    fn print(&self) {
+        for row in self.distances.chunks(self.width) {
+            let mut text = String::new();
+            for &distance in row {
+                if distance == usize::MAX {
+                    text.push_str("--- ");
+                } else {
+                    text.push_str(&format!("{:03} ", distance));
+                }
             }
+            println!("{}", text);
         }
     }
+}
 
-    // for y in 0..map.height() {
-    //     let mut row = String::new();
-    //     for x in 0..map.width {
-    //         row.push_str(&format!("{:03} ", distances.get(&(x, y)).unwrap_or(&999)));
-    //     }
-    //     println!("{}", row);
-    // }
-
-    *goals
-        .iter()
-        .filter_map(|goal| distances.get(goal))
-        .min()
-        .unwrap()
+// Searches outward from whichever of sources and targets is smaller
+fn shortest_route(
+    map: &HeightMap,
+    sources: &[(usize, usize)],
+    targets: &[(usize, usize)],
+) -> Option<Route> {
+    if sources.len() <= targets.len() {
+        DistanceField::compute(map, sources, false).nearest(targets)
+    } else {
+        DistanceField::compute(map, targets, true).nearest(sources)
+    }
 }
 
 fn main() {
     let input = std::fs::read_to_string("input.txt").unwrap();
+    let options = Options::from_args(std::env::args().skip(1));
+
+    let (mut map, start, goal) = HeightMap::parse(&input);
+    map.rules = options.rules;
 
-    let (map, start, goal) = HeightMap::parse(&input);
-    println!("{}", len_of_shortest_path(start, &[goal], &map, false));
-
-    let possible_starts = map.lowest_points.clone();
-    let shortest_possible_climb = len_of_shortest_path(goal, &possible_starts[..], &map, true);
-    // let shortest_possible_climb = possible_starts
-    //     .into_iter()
-    //     .map(|point| len_of_shortest_path(point, goal, &map))
-    //     .min()
-    //     .unwrap();
-    println!("{}", shortest_possible_climb);
+    let lowest_points = map.cells_with_height(0).collect::<Vec<_>>();
+    for sources in [vec![start], lowest_points] {
+        match shortest_route(&map, &sources, &[goal]) {
+            Some(route) => println!("{}", route.distance),
+            None => println!("unreachable"),
+        }
+    }
+
+    // queries sharing a source set are answered from a single distance field
+    let mut queries = options.queries;
+    queries.sort_by(|a, b| a.from.cmp(&b.from));
+    for group in queries.chunk_by(|a, b| a.from == b.from) {
+        let sources = map.resolve_cells(&group[0].from, start, goal);
+        let field = DistanceField::compute(&map, &sources, false);
+
+        for query in group {
+            let targets = map.resolve_cells(&query.to, start, goal);
+            match field.nearest(&targets) {
+                Some(route) => println!(
+                    "{} -> {}: {} ({:?} -> {:?})",
+                    query.from, query.to, route.distance, route.source, route.target
+                ),
+                None => println!("{} -> {}: unreachable", query.from, query.to),
+            }
+        }
+    }
 }
@@ -1,105 +1,477 @@
-use std::cmp::Ordering;
+use std::{cmp::Ordering, fmt};
 
-fn split_at_next_comma(s: &[u8]) -> (&[u8], &[u8]) {
-    let mut depth = 0;
-    let mut idx = 0;
-    loop {
-        if idx >= s.len() {
-            return (s, &[]);
+const DEFAULT_MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum ParseErrorKind {
+    UnexpectedEnd,
+    UnexpectedByte(u8),
+    NumberOutOfRange,
+    TooDeep(usize),
+    TrailingInput,
+}
+
+// Byte offset the parse failed at
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ParseError {
+    offset: usize,
+    kind: ParseErrorKind,
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ParseErrorKind::UnexpectedEnd => write!(f, "unexpected end of input")?,
+            ParseErrorKind::UnexpectedByte(byte) => {
+                write!(f, "unexpected {:?}", byte.escape_ascii().to_string())?
+            }
+            ParseErrorKind::NumberOutOfRange => write!(f, "number out of range")?,
+            ParseErrorKind::TooDeep(limit) => write!(f, "lists nested deeper than {}", limit)?,
+            ParseErrorKind::TrailingInput => write!(f, "unexpected input after packet")?,
         }
+        write!(f, " at byte {}", self.offset)
+    }
+}
 
-        if s[idx] == b'[' {
-            depth += 1;
-        } else if s[idx] == b']' {
-            depth -= 1;
+struct Parser<'a> {
+    s: &'a [u8],
+    idx: usize,
+    max_depth: usize,
+}
+impl<'a> Parser<'a> {
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError {
+            offset: self.idx,
+            kind,
         }
+    }
 
-        if s[idx] == b',' && depth == 0 {
-            return (&s[0..idx], &s[idx + 1..]);
+    fn skip_whitespace(&mut self) {
+        while self.s.get(self.idx).is_some_and(u8::is_ascii_whitespace) {
+            self.idx += 1;
         }
+    }
 
-        idx += 1;
+    fn peek(&mut self) -> Result<u8, ParseError> {
+        self.skip_whitespace();
+        self.s
+            .get(self.idx)
+            .copied()
+            .ok_or_else(|| self.error(ParseErrorKind::UnexpectedEnd))
+    }
+
+    fn packet(&mut self, depth: usize) -> Result<Packet, ParseError> {
+        match self.peek()? {
+            b'[' => self.list(depth),
+            b'-' | b'0'..=b'9' => self.number(),
+            byte => Err(self.error(ParseErrorKind::UnexpectedByte(byte))),
+        }
+    }
+
+    fn list(&mut self, depth: usize) -> Result<Packet, ParseError> {
+        if depth >= self.max_depth {
+            return Err(self.error(ParseErrorKind::TooDeep(self.max_depth)));
+        }
+
+        // opening bracket
+        self.idx += 1;
+
+        let mut result = Vec::new();
+        if self.peek()? == b']' {
+            self.idx += 1;
+            return Ok(Packet::List(result));
+        }
+
+        loop {
+            result.push(self.packet(depth + 1)?);
+
+            match self.peek()? {
+                b',' => self.idx += 1,
+                b']' => {
+                    self.idx += 1;
+                    return Ok(Packet::List(result));
+                }
+                byte => return Err(self.error(ParseErrorKind::UnexpectedByte(byte))),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Packet, ParseError> {
+        let start = self.idx;
+        if self.s[self.idx] == b'-' {
+            self.idx += 1;
+        }
+
+        let digits_start = self.idx;
+        while self.s.get(self.idx).is_some_and(u8::is_ascii_digit) {
+            self.idx += 1;
+        }
+        if self.idx == digits_start {
+            return Err(match self.s.get(self.idx) {
+                Some(&byte) => self.error(ParseErrorKind::UnexpectedByte(byte)),
+                None => self.error(ParseErrorKind::UnexpectedEnd),
+            });
+        }
+
+        // the slice is only ASCII digits and an optional sign, so the only possible failure is
+        // overflow
+        std::str::from_utf8(&self.s[start..self.idx])
+            .unwrap()
+            .parse()
+            .map(Packet::Number)
+            .map_err(|_| ParseError {
+                offset: start,
+                kind: ParseErrorKind::NumberOutOfRange,
+            })
     }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 enum Packet {
-    Number(u32),
+    Number(i64),
     List(Vec<Packet>),
 }
 impl Packet {
-    fn parse(s: &[u8]) -> Option<Self> {
-        if s.is_empty() {
-            None
-        } else if s[0] == b'[' {
-            let mut result = Vec::new();
-
-            let mut substr = &s[1..s.len() - 1];
-            while !substr.is_empty() {
-                let (child_element, remainder) = split_at_next_comma(substr);
-                result.push(Packet::parse(child_element).unwrap());
-                substr = remainder;
-            }
+    #[cfg(test)]
+    fn parse(s: &[u8]) -> Result<Self, ParseError> {
+        Self::parse_with_max_depth(s, DEFAULT_MAX_DEPTH)
+    }
 
-            Some(Packet::List(result))
+    fn parse_with_max_depth(s: &[u8], max_depth: usize) -> Result<Self, ParseError> {
+        let mut parser = Parser {
+            s,
+            idx: 0,
+            max_depth,
+        };
+
+        let result = parser.packet(0)?;
+        parser.skip_whitespace();
+        if parser.idx != s.len() {
+            return Err(parser.error(ParseErrorKind::TrailingInput));
+        }
+
+        Ok(result)
+    }
+}
+// JSON pointer to the value that isn't a packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct JsonError {
+    path: String,
+    reason: &'static str,
+}
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{} at top level", self.reason)
         } else {
-            Some(Packet::Number(
-                std::str::from_utf8(s).unwrap().parse().unwrap(),
-            ))
+            write!(f, "{} at {}", self.reason, self.path)
+        }
+    }
+}
+
+impl Packet {
+    fn from_json(value: &serde_json::Value) -> Result<Self, JsonError> {
+        Self::from_json_at(value, &mut String::new())
+    }
+
+    fn from_json_at(value: &serde_json::Value, path: &mut String) -> Result<Self, JsonError> {
+        use serde_json::Value;
+
+        let error = |path: &String, reason| {
+            Err(JsonError {
+                path: path.clone(),
+                reason,
+            })
+        };
+
+        match value {
+            Value::Number(number) => match number.as_i64() {
+                Some(value) => Ok(Packet::Number(value)),
+                None if number.is_f64() => error(path, "floating point numbers are not allowed"),
+                None => error(path, "number out of range"),
+            },
+            Value::Array(children) => {
+                let mut result = Vec::with_capacity(children.len());
+                for (idx, child) in children.iter().enumerate() {
+                    let path_len = path.len();
+                    path.push_str(&format!("/{}", idx));
+                    result.push(Self::from_json_at(child, path)?);
+                    path.truncate(path_len);
+                }
+                Ok(Packet::List(result))
+            }
+            Value::Object(_) => error(path, "objects are not allowed"),
+            Value::String(_) => error(path, "strings are not allowed"),
+            Value::Bool(_) => error(path, "booleans are not allowed"),
+            Value::Null => error(path, "null is not allowed"),
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        match self {
+            Packet::Number(value) => serde_json::Value::from(*value),
+            Packet::List(children) => children.iter().map(Packet::to_json).collect(),
+        }
+    }
+}
+impl fmt::Display for Packet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Packet::Number(value) => write!(f, "{}", value),
+            Packet::List(children) => {
+                write!(f, "[")?;
+                for (idx, child) in children.iter().enumerate() {
+                    if idx > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", child)?;
+                }
+                write!(f, "]")
+            }
         }
     }
 }
 impl PartialOrd for Packet {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Packet {
+    fn cmp(&self, other: &Self) -> Ordering {
         use Packet::*;
 
-        let mut idx = 0;
-        loop {
-            let comparison_result = match (self, other) {
-                (Number(left), Number(right)) => {
-                    return left.partial_cmp(right);
-                }
-                (Number(left), List(_)) => {
-                    return (Packet::List(vec![Packet::Number(*left)])).partial_cmp(other);
+        // a number compared against a list is treated as a one-element list containing it;
+        // borrowing it as a slice avoids allocating that list
+        match (self, other) {
+            (Number(left), Number(right)) => left.cmp(right),
+            (Number(_), List(right)) => cmp_lists(std::slice::from_ref(self), right),
+            (List(left), Number(_)) => cmp_lists(left, std::slice::from_ref(other)),
+            (List(left), List(right)) => cmp_lists(left, right),
+        }
+    }
+}
+
+fn cmp_lists(left: &[Packet], right: &[Packet]) -> Ordering {
+    for (l, r) in left.iter().zip(right.iter()) {
+        match l.cmp(r) {
+            Ordering::Equal => {}
+            result => return result,
+        }
+    }
+
+    left.len().cmp(&right.len())
+}
+
+// Old comparison that allocates when promoting numbers, kept for the benchmark
+fn allocating_cmp(this: &Packet, other: &Packet) -> Ordering {
+    use Packet::*;
+
+    let mut idx = 0;
+    loop {
+        let comparison_result = match (this, other) {
+            (Number(left), Number(right)) => {
+                return left.cmp(right);
+            }
+            (Number(left), List(_)) => {
+                return allocating_cmp(&Packet::List(vec![Packet::Number(*left)]), other);
+            }
+            (List(_), Number(right)) => {
+                return allocating_cmp(this, &Packet::List(vec![Packet::Number(*right)]));
+            }
+            (List(left), List(right)) => {
+                if idx >= left.len() && idx < right.len() {
+                    return Ordering::Less;
+                } else if idx < left.len() && idx >= right.len() {
+                    return Ordering::Greater;
+                } else if idx >= left.len() || idx >= right.len() {
+                    return Ordering::Equal;
                 }
-                (List(_), Number(right)) => {
-                    return self.partial_cmp(&Packet::List(vec![Packet::Number(*right)]));
+
+                allocating_cmp(&left[idx], &right[idx])
+            }
+        };
+
+        match comparison_result {
+            Ordering::Equal => idx += 1,
+            _ => return comparison_result,
+        }
+    }
+}
+
+// xorshift, to avoid pulling in a crate
+struct Rng(u64);
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, max: u64) -> u64 {
+        self.next() % max
+    }
+}
+
+// Narrow and deeply nested, with small numbers so comparisons descend all the way
+fn deep_packet(rng: &mut Rng, depth: u32) -> Packet {
+    if depth == 0 {
+        return Packet::Number(rng.below(3) as i64);
+    }
+
+    let len = 1 + rng.below(3);
+    Packet::List(
+        (0..len)
+            .map(|idx| {
+                if idx == 0 && rng.below(8) != 0 {
+                    deep_packet(rng, depth - 1)
+                } else {
+                    Packet::Number(rng.below(3) as i64)
                 }
-                (List(left), List(right)) => {
-                    if idx >= left.len() && idx < right.len() {
-                        return Some(Ordering::Less);
-                    } else if idx < left.len() && idx >= right.len() {
-                        return Some(Ordering::Greater);
-                    } else if idx >= left.len() || idx >= right.len() {
-                        return Some(Ordering::Equal);
-                    }
+            })
+            .collect(),
+    )
+}
+
+fn benchmark_sort(count: usize, depth: u32) {
+    let mut rng = Rng(0x2022_1213);
+    let packets = (0..count)
+        .map(|_| deep_packet(&mut rng, depth))
+        .collect::<Vec<_>>();
+
+    let mut sorted = packets.clone();
+    let start = std::time::Instant::now();
+    sorted.sort();
+    println!("borrowing comparison: {:?}", start.elapsed());
+
+    let mut allocating_sorted = packets;
+    let start = std::time::Instant::now();
+    allocating_sorted.sort_by(allocating_cmp);
+    println!("allocating comparison: {:?}", start.elapsed());
+
+    assert!(sorted == allocating_sorted, "comparisons disagree");
+}
 
-                    left[idx].partial_cmp(&right[idx])
+// Same rules as Packet::cmp, printing each step
+fn explain_cmp(left: &Packet, right: &Packet, indent: usize) -> Ordering {
+    use Packet::*;
+
+    let pad = "  ".repeat(indent);
+    println!("{}- Compare {} vs {}", pad, left, right);
+
+    match (left, right) {
+        (Number(l), Number(r)) => {
+            let result = l.cmp(r);
+            match result {
+                Ordering::Less => println!("{}  - Left side is smaller", pad),
+                Ordering::Greater => println!("{}  - Right side is smaller", pad),
+                Ordering::Equal => {}
+            }
+            result
+        }
+        (Number(l), List(_)) => {
+            let promoted = List(vec![Number(*l)]);
+            println!(
+                "{}  - Mixed types; convert left to {} and retry comparison",
+                pad, promoted
+            );
+            explain_cmp(&promoted, right, indent + 1)
+        }
+        (List(_), Number(r)) => {
+            let promoted = List(vec![Number(*r)]);
+            println!(
+                "{}  - Mixed types; convert right to {} and retry comparison",
+                pad, promoted
+            );
+            explain_cmp(left, &promoted, indent + 1)
+        }
+        (List(l), List(r)) => {
+            for (l, r) in l.iter().zip(r.iter()) {
+                let result = explain_cmp(l, r, indent + 1);
+                if result != Ordering::Equal {
+                    return result;
                 }
-            };
+            }
 
-            match comparison_result {
-                Some(Ordering::Equal) => idx += 1,
-                _ => return comparison_result,
+            let result = l.len().cmp(&r.len());
+            match result {
+                Ordering::Less => println!("{}  - Left side ran out of items", pad),
+                Ordering::Greater => println!("{}  - Right side ran out of items", pad),
+                Ordering::Equal => {}
             }
+            result
         }
     }
 }
-impl Ord for Packet {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+
+fn parse_groups(input: &str, max_depth: usize) -> Vec<Vec<Packet>> {
+    let mut result = vec![Vec::new()];
+
+    for (idx, line) in input.lines().enumerate() {
+        if line.trim().is_empty() {
+            if !result.last().unwrap().is_empty() {
+                result.push(Vec::new());
+            }
+            continue;
+        }
+
+        let packet = Packet::parse_with_max_depth(line.as_bytes(), max_depth)
+            .unwrap_or_else(|err| panic!("line {}: {}", idx + 1, err));
+        result.last_mut().unwrap().push(packet);
+    }
+
+    if result.last().unwrap().is_empty() {
+        result.pop();
     }
+    result
 }
 
-fn main() {
-    let input = std::fs::read_to_string("input.txt").unwrap();
-    let mut packets = input
+// One packet per line, consecutive packets grouped into pairs
+fn parse_json_lines(input: &str) -> Vec<Vec<Packet>> {
+    let packets = input
         .lines()
-        .filter_map(|line| Packet::parse(line.as_bytes()))
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| {
+            let value: serde_json::Value = serde_json::from_str(line)
+                .unwrap_or_else(|err| panic!("line {}: {}", idx + 1, err));
+            Packet::from_json(&value).unwrap_or_else(|err| panic!("line {}: {}", idx + 1, err))
+        })
         .collect::<Vec<_>>();
 
-    let divider_0 = Packet::List(vec![Packet::List(vec![Packet::Number(2)])]);
-    let divider_1 = Packet::List(vec![Packet::List(vec![Packet::Number(6)])]);
+    packets.chunks(2).map(<[Packet]>::to_vec).collect()
+}
+
+fn into_pairs(groups: Vec<Vec<Packet>>) -> Vec<(Packet, Packet)> {
+    groups
+        .into_iter()
+        .enumerate()
+        .map(|(idx, group)| {
+            let Ok([left, right]) = <[Packet; 2]>::try_from(group) else {
+                panic!("group {} is not a pair of packets", idx + 1);
+            };
+            (left, right)
+        })
+        .collect()
+}
+
+fn sum_of_ordered_pair_indices(pairs: &[(Packet, Packet)]) -> usize {
+    pairs
+        .iter()
+        .enumerate()
+        .filter(|(_, (left, right))| left < right)
+        .map(|(idx, _)| idx + 1)
+        .sum()
+}
+
+fn divider_packets() -> [Packet; 2] {
+    [
+        Packet::List(vec![Packet::List(vec![Packet::Number(2)])]),
+        Packet::List(vec![Packet::List(vec![Packet::Number(6)])]),
+    ]
+}
+
+fn decoder_key(mut packets: Vec<Packet>) -> usize {
+    let [divider_0, divider_1] = divider_packets();
 
     packets.push(divider_0.clone());
     packets.push(divider_1.clone());
@@ -109,5 +481,125 @@ fn main() {
     let divider_0_idx = packets.binary_search(&divider_0).unwrap();
     let divider_1_idx = packets.binary_search(&divider_1).unwrap();
 
-    println!("{}", (divider_0_idx + 1) * (divider_1_idx + 1));
+    (divider_0_idx + 1) * (divider_1_idx + 1)
+}
+
+fn print_sorted(mut packets: Vec<Packet>) {
+    let dividers = divider_packets();
+    packets.extend(dividers.iter().cloned());
+    packets.sort();
+
+    for packet in packets {
+        if dividers.contains(&packet) {
+            println!("{} <- divider", packet);
+        } else {
+            println!("{}", packet);
+        }
+    }
+}
+
+fn main() {
+    let mut positional = Vec::new();
+    let mut max_depth = DEFAULT_MAX_DEPTH;
+    let mut json_lines = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--max-depth" {
+            max_depth = args.next().unwrap().parse().unwrap();
+        } else if arg == "--jsonl" {
+            json_lines = Some(args.next().unwrap());
+        } else {
+            positional.push(arg);
+        }
+    }
+
+    let groups = match json_lines {
+        Some(path) => parse_json_lines(&std::fs::read_to_string(path).unwrap()),
+        None => parse_groups(&std::fs::read_to_string("input.txt").unwrap(), max_depth),
+    };
+
+    match positional.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => {
+            let pairs = into_pairs(groups);
+            println!("{}", sum_of_ordered_pair_indices(&pairs));
+
+            let packets = pairs.into_iter().flat_map(|(l, r)| [l, r]).collect();
+            println!("{}", decoder_key(packets));
+        }
+        ["explain", pair] => {
+            let pairs = into_pairs(groups);
+            let index = pair.parse::<usize>().unwrap();
+            assert!(
+                (1..=pairs.len()).contains(&index),
+                "pair {} is out of range, expected 1 to {}",
+                index,
+                pairs.len()
+            );
+            let (left, right) = &pairs[index - 1];
+
+            let result = explain_cmp(left, right, 0);
+            debug_assert_eq!(result, left.cmp(right));
+            match result {
+                Ordering::Less => println!("Inputs are in the right order"),
+                Ordering::Greater => println!("Inputs are not in the right order"),
+                Ordering::Equal => println!("Inputs are equal"),
+            }
+        }
+        ["sort"] => print_sorted(groups.concat()),
+        ["bench"] => benchmark_sort(50_000, 64),
+        ["tojson"] => {
+            for packet in groups.concat() {
+                println!("{}", packet.to_json());
+            }
+        }
+        ref other => panic!("unknown subcommand {:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn random_packet(rng: &mut Rng, depth: u32) -> Packet {
+        if depth == 0 || rng.below(3) == 0 {
+            match rng.below(10) {
+                0 => Packet::Number(rng.next() as i64),
+                1 => Packet::Number(-(rng.below(20) as i64)),
+                _ => Packet::Number(rng.below(20) as i64),
+            }
+        } else {
+            let len = rng.below(5);
+            Packet::List((0..len).map(|_| random_packet(rng, depth - 1)).collect())
+        }
+    }
+
+    #[test]
+    fn example_packets_round_trip() {
+        for text in [
+            "[1,1,3,1,1]",
+            "[[1],[2,3,4]]",
+            "[[[]]]",
+            "[1,[2,[3,[4,[5,6,0]]]],8,9]",
+        ] {
+            let packet = Packet::parse(text.as_bytes()).unwrap();
+            assert_eq!(packet.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn random_packets_round_trip() {
+        let mut rng = Rng(0x2022_1213);
+        for _ in 0..10_000 {
+            let packet = random_packet(&mut rng, 6);
+            let text = packet.to_string();
+            assert_eq!(
+                Packet::parse(text.as_bytes()),
+                Ok(packet.clone()),
+                "{}",
+                text
+            );
+            assert_eq!(Packet::from_json(&packet.to_json()), Ok(packet));
+        }
+    }
 }
@@ -1,19 +1,209 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::RangeInclusive,
+    str::FromStr,
+};
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum Tile {
     Air,
     Rock,
     Sand,
+    Water,
+    Gravel,
+}
+impl Tile {
+    fn symbol(self) -> char {
+        match self {
+            Tile::Air => ' ',
+            Tile::Rock => '#',
+            Tile::Sand => '.',
+            Tile::Water => '~',
+            Tile::Gravel => 'o',
+        }
+    }
+}
+impl FromStr for Tile {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sand" => Ok(Tile::Sand),
+            "water" => Ok(Tile::Water),
+            "gravel" => Ok(Tile::Gravel),
+            _ => Err(()),
+        }
+    }
+}
+
+// Tries each of moves in order until one leads into can_enter, otherwise comes to rest
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FallRule {
+    moves: Vec<(i32, i32)>,
+    can_enter: Vec<Tile>,
+}
+impl FallRule {
+    fn for_particle(particle: Tile) -> Self {
+        match particle {
+            // straight down, then diagonally left, then diagonally right
+            Tile::Sand => Self {
+                moves: vec![(0, 1), (-1, 1), (1, 1)],
+                can_enter: vec![Tile::Air],
+            },
+            // like sand, but flows sideways along whatever it lands on
+            Tile::Water => Self {
+                moves: vec![(0, 1), (-1, 1), (1, 1), (-1, 0), (1, 0)],
+                can_enter: vec![Tile::Air],
+            },
+            // like sand, but heavy enough to sink through water, displacing it if it settles there
+            Tile::Gravel => Self {
+                moves: vec![(0, 1), (-1, 1), (1, 1)],
+                can_enter: vec![Tile::Air, Tile::Water],
+            },
+            Tile::Air | Tile::Rock => panic!("{:?} doesn't fall", particle),
+        }
+    }
+
+    // Only possible when moving sideways or upwards
+    fn can_revisit(&self) -> bool {
+        self.moves.iter().any(|&(_, dy)| dy <= 0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Source {
+    pos: (i32, i32),
+    particle: Tile,
+    rule: FallRule,
+}
+impl Source {
+    fn new(pos: (i32, i32), particle: Tile) -> Self {
+        Self {
+            pos,
+            particle,
+            rule: FallRule::for_particle(particle),
+        }
+    }
+}
+impl FromStr for Source {
+    type Err = ();
+
+    // x,y[,material[,moves]] with moves as dx:dy pairs separated by ; e.g. 500,0,water,0:1;-1:0;1:0
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let x = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let y = parts.next().ok_or(())?.parse().map_err(|_| ())?;
+        let particle = parts.next().map_or(Ok(Tile::Sand), str::parse)?;
+
+        let mut result = Source::new((x, y), particle);
+        if let Some(moves) = parts.next() {
+            result.rule.moves = moves
+                .split(';')
+                .map(|step| {
+                    let (dx, dy) = step.split_once(':').ok_or(())?;
+                    Ok((dx.parse().map_err(|_| ())?, dy.parse().map_err(|_| ())?))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+
+        if parts.next().is_some() {
+            return Err(());
+        }
+        Ok(result)
+    }
+}
+
+// What lies below the lowest rock
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Boundary {
+    // Anything falling past the lowest rock is lost
+    Abyss,
+    // Infinitely wide, two below the lowest rock
+    Floor,
+    // Infinitely wide, at the given depth
+    FloorAt(i32),
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum TileStorage {
+    // Only non-air tiles, so the map can grow in any direction
+    Sparse(HashMap<(i32, i32), Tile>),
+    // Every tile in a fixed rectangle, anything outside is air
+    Dense {
+        min_x: i32,
+        min_y: i32,
+        width: usize,
+        height: usize,
+        tiles: Vec<Tile>,
+    },
+}
+impl Default for TileStorage {
+    fn default() -> Self {
+        Self::Sparse(HashMap::new())
+    }
+}
+impl TileStorage {
+    fn dense_index(
+        x: i32,
+        y: i32,
+        min_x: i32,
+        min_y: i32,
+        width: usize,
+        height: usize,
+    ) -> Option<usize> {
+        let x = usize::try_from(x - min_x).ok()?;
+        let y = usize::try_from(y - min_y).ok()?;
+        (x < width && y < height).then_some(y * width + x)
+    }
+
+    fn get(&self, x: i32, y: i32) -> Tile {
+        match self {
+            TileStorage::Sparse(tiles) => tiles.get(&(x, y)).copied().unwrap_or(Tile::Air),
+            TileStorage::Dense {
+                min_x,
+                min_y,
+                width,
+                height,
+                tiles,
+            } => Self::dense_index(x, y, *min_x, *min_y, *width, *height)
+                .map_or(Tile::Air, |idx| tiles[idx]),
+        }
+    }
+
+    fn set(&mut self, x: i32, y: i32, tile: Tile) {
+        match self {
+            TileStorage::Sparse(tiles) => {
+                tiles.insert((x, y), tile);
+            }
+            TileStorage::Dense {
+                min_x,
+                min_y,
+                width,
+                height,
+                tiles,
+            } => {
+                let idx = Self::dense_index(x, y, *min_x, *min_y, *width, *height)
+                    .unwrap_or_else(|| panic!("({}, {}) is outside the dense tile storage", x, y));
+                tiles[idx] = tile;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Storage {
+    Sparse,
+    Dense,
+}
+
+#[derive(Clone, Debug)]
 struct Map {
     min_x: i32,
     max_x: i32,
     min_y: i32,
     max_y: i32,
-    tiles: HashMap<(i32, i32), Tile>,
+    boundary: Boundary,
+    tiles: TileStorage,
 }
 impl Default for Map {
     fn default() -> Self {
@@ -22,17 +212,101 @@ impl Default for Map {
             max_x: i32::MIN,
             min_y: i32::MAX,
             max_y: i32::MIN,
+            boundary: Boundary::Floor,
             tiles: Default::default(),
         }
     }
 }
 impl Map {
-    fn drop_sand_from(&mut self, mut x: i32, mut y: i32) -> (i32, i32) {
+    fn floor_depth(&self) -> Option<i32> {
+        match self.boundary {
+            Boundary::Abyss => None,
+            Boundary::Floor => Some(self.max_y + 2),
+            Boundary::FloorAt(depth) => Some(depth),
+        }
+    }
+
+    // All the rock plus anywhere sand can reach, sides act as walls when there's a floor
+    fn particle_region(&self, sources: &[Source]) -> (RangeInclusive<i32>, RangeInclusive<i32>) {
+        let depth = self.floor_depth().unwrap_or(self.max_y + 1);
+
+        let mut min_x = self.min_x;
+        let mut max_x = self.max_x;
+        let mut min_y = self.min_y;
+        for &Source { pos: (x, y), .. } in sources {
+            let spread = depth - y;
+            min_x = min_x.min(x - spread);
+            max_x = max_x.max(x + spread);
+            min_y = min_y.min(y);
+        }
+
+        (min_x..=max_x, min_y..=(depth - 1))
+    }
+
+    // Dense storage covers the particle region for sources
+    fn set_storage(&mut self, storage: Storage, sources: &[Source]) {
+        let mut new_tiles = match storage {
+            Storage::Sparse => TileStorage::Sparse(HashMap::new()),
+            Storage::Dense => {
+                let (xs, ys) = self.particle_region(sources);
+                let min_y = *ys.start().min(&self.min_y);
+                let max_y = *ys.end().max(&self.max_y);
+
+                let width = (xs.end() - xs.start() + 1) as usize;
+                let height = (max_y - min_y + 1) as usize;
+                TileStorage::Dense {
+                    min_x: *xs.start(),
+                    min_y,
+                    width,
+                    height,
+                    tiles: vec![Tile::Air; width * height],
+                }
+            }
+        };
+
+        for y in self.min_y..=self.max_y {
+            for x in self.min_x..=self.max_x {
+                let tile = self.tiles.get(x, y);
+                if tile != Tile::Air {
+                    new_tiles.set(x, y, tile);
+                }
+            }
+        }
+
+        self.tiles = new_tiles;
+    }
+
+    // Returns where it came to rest, or None if it left region
+    fn drop_particle(
+        &mut self,
+        source: &Source,
+        region: &(RangeInclusive<i32>, RangeInclusive<i32>),
+    ) -> Option<(i32, i32)> {
+        let (mut x, mut y) = source.pos;
+        let mut visited = HashSet::new();
+        let has_floor = self.floor_depth().is_some();
+
         'falling: loop {
-            let new_y = y + 1;
+            if !region.0.contains(&x) || !region.1.contains(&y) {
+                return None;
+            }
 
-            for new_x in [x, x - 1, x + 1] {
-                if self.get_tile_at(new_x, new_y) == Tile::Air {
+            if source.rule.can_revisit() {
+                visited.insert((x, y));
+            }
+
+            for &(dx, dy) in &source.rule.moves {
+                let (new_x, new_y) = (x + dx, y + dy);
+                if has_floor && !region.0.contains(&new_x) {
+                    continue;
+                }
+
+                if source
+                    .rule
+                    .can_enter
+                    .contains(&self.get_tile_at(new_x, new_y))
+                    && !visited.contains(&(new_x, new_y))
+                {
                     x = new_x;
                     y = new_y;
                     continue 'falling;
@@ -40,8 +314,8 @@ impl Map {
             }
 
             // none of the new spaces were empty
-            self.set_tile_at(x, y, Tile::Sand, false);
-            return (x, y);
+            self.set_tile_at(x, y, source.particle, false);
+            return Some((x, y));
         }
     }
 
@@ -54,26 +328,22 @@ impl Map {
             self.max_y = self.max_y.max(y);
         }
 
-        self.tiles.insert((x, y), tile);
+        self.tiles.set(x, y, tile);
     }
 
     fn get_tile_at(&self, x: i32, y: i32) -> Tile {
-        if y == (self.max_y + 2) {
+        if self.floor_depth().is_some_and(|depth| y >= depth) {
             Tile::Rock
         } else {
-            self.tiles.get(&(x, y)).copied().unwrap_or(Tile::Air)
+            self.tiles.get(x, y)
         }
     }
 
     fn print(&self) {
-        for y in self.min_y..=(self.max_y + 2) {
+        for y in self.min_y..=self.floor_depth().unwrap_or(self.max_y) {
             let mut text = String::new();
             for x in self.min_x..=self.max_x {
-                text.push(match self.get_tile_at(x, y) {
-                    Tile::Air => ' ',
-                    Tile::Rock => '#',
-                    Tile::Sand => '.',
-                });
+                text.push(self.get_tile_at(x, y).symbol());
             }
             println!("{}", text);
         }
@@ -121,19 +391,230 @@ impl FromStr for Map {
     }
 }
 
+// Returns how many came to rest, sources retire once they're blocked or lose a particle
+fn fill_with_particles(map: &mut Map, sources: &[Source]) -> usize {
+    let region = map.particle_region(sources);
+
+    let mut particle_count = 0;
+    let mut active = sources.iter().collect::<Vec<_>>();
+    while !active.is_empty() {
+        active.retain(|source| {
+            let (x, y) = source.pos;
+            if !source.rule.can_enter.contains(&map.get_tile_at(x, y)) {
+                return false;
+            }
+
+            match map.drop_particle(source, &region) {
+                Some(resting_place) => {
+                    // map.print();
+                    particle_count += 1;
+                    resting_place != source.pos
+                }
+                None => false,
+            }
+        });
+    }
+
+    particle_count
+}
+
+// Resumes each grain from the last free position on the previous grain's path
+fn fill_with_sand_memoised(map: &mut Map, source: (i32, i32)) -> usize {
+    let mut sand_count = 0;
+    let mut path = vec![source];
+
+    while let Some(&(x, y)) = path.last() {
+        if map.boundary == Boundary::Abyss && y > map.max_y {
+            // every following grain would take the same path into the abyss
+            break;
+        }
+
+        let new_y = y + 1;
+        match [x, x - 1, x + 1]
+            .into_iter()
+            .find(|&new_x| map.get_tile_at(new_x, new_y) == Tile::Air)
+        {
+            Some(new_x) => path.push((new_x, new_y)),
+            None => {
+                map.set_tile_at(x, y, Tile::Sand, false);
+                sand_count += 1;
+                path.pop();
+            }
+        }
+    }
+
+    sand_count
+}
+
+// With a floor, a tile fills if it isn't rock and one of the three above it is filled
+fn fill_with_sand_triangle(map: &mut Map, source: (i32, i32)) -> usize {
+    let floor = map
+        .floor_depth()
+        .expect("triangle counting requires a floor");
+
+    let mut sand_count = 0;
+    let mut row = HashSet::from([source.0]);
+
+    for y in source.1..floor {
+        if row.is_empty() {
+            break;
+        }
+
+        for &x in &row {
+            map.set_tile_at(x, y, Tile::Sand, false);
+        }
+        sand_count += row.len();
+
+        row = row
+            .iter()
+            .flat_map(|&x| [x - 1, x, x + 1])
+            .filter(|&x| map.get_tile_at(x, y + 1) == Tile::Air)
+            .collect();
+    }
+
+    sand_count
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Simulate,
+    Memoised,
+    Triangle,
+}
+
+fn count_resting_particles(map: &mut Map, sources: &[Source], mode: Mode) -> usize {
+    match mode {
+        Mode::Simulate => fill_with_particles(map, sources),
+        Mode::Memoised => fill_with_sand_memoised(map, single_sand_source(sources, mode)),
+        // sand falling into the abyss doesn't form a triangle
+        Mode::Triangle if map.boundary == Boundary::Abyss => {
+            fill_with_sand_memoised(map, single_sand_source(sources, mode))
+        }
+        Mode::Triangle => fill_with_sand_triangle(map, single_sand_source(sources, mode)),
+    }
+}
+
+// The position of the only source, for modes that can't handle anything but plain sand
+fn single_sand_source(sources: &[Source], mode: Mode) -> (i32, i32) {
+    let [source] = sources else {
+        panic!("{:?} only supports a single source", mode);
+    };
+    assert!(
+        *source == Source::new(source.pos, Tile::Sand),
+        "{:?} only supports plain sand",
+        mode
+    );
+
+    source.pos
+}
+
+fn verify_modes(map: &Map, sources: &[Source], storage: Storage) {
+    for boundary in [Boundary::Abyss, map.boundary] {
+        let counts = [Mode::Simulate, Mode::Memoised, Mode::Triangle].map(|mode| {
+            let mut map = Map {
+                boundary,
+                ..map.clone()
+            };
+            map.set_storage(storage, sources);
+            let count = count_resting_particles(&mut map, sources, mode);
+            (count, map.tiles)
+        });
+
+        for (mode, (count, tiles)) in [Mode::Memoised, Mode::Triangle].iter().zip(&counts[1..]) {
+            assert_eq!(
+                counts[0].0, *count,
+                "{:?} disagrees with {:?}",
+                mode, boundary
+            );
+            assert!(
+                counts[0].1 == *tiles,
+                "{:?} fills differently with {:?}",
+                mode,
+                boundary
+            );
+        }
+
+        println!("{:?}: all modes agree on {}", boundary, counts[0].0);
+    }
+
+    // Water spreads sideways further than sand can, check it still pools up on the floor
+    let water = [Source::new(sources[0].pos, Tile::Water)];
+    let mut water_map = map.clone();
+    water_map.set_storage(storage, &water);
+    let water_count = fill_with_particles(&mut water_map, &water);
+    assert!(
+        water_count > 0,
+        "water from {:?} never settles",
+        water[0].pos
+    );
+    println!("{:?}: water settles {}", map.boundary, water_count);
+}
+
 fn main() {
     let mut map: Map = std::fs::read_to_string("input.txt")
         .unwrap()
         .parse()
         .unwrap();
-    map.print();
 
-    let mut sand_count = 0;
-    while map.drop_sand_from(500, 0) != (500, 0) {
-        // map.print();
-        sand_count += 1;
+    let mut mode = Mode::Simulate;
+    let mut verify = false;
+    let mut storage = Storage::Dense;
+    let mut sources = Vec::new();
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--floor-at" => map.boundary = Boundary::FloorAt(args.next().unwrap().parse().unwrap()),
+            "--mode" => {
+                mode = match args.next().unwrap().as_str() {
+                    "simulate" => Mode::Simulate,
+                    "memoised" => Mode::Memoised,
+                    "triangle" => Mode::Triangle,
+                    other => panic!("unknown mode {}", other),
+                }
+            }
+            "--verify" => verify = true,
+            "--storage" => {
+                storage = match args.next().unwrap().as_str() {
+                    "sparse" => Storage::Sparse,
+                    "dense" => Storage::Dense,
+                    other => panic!("unknown storage {}", other),
+                }
+            }
+            "--source" => {
+                let source = args.next().unwrap();
+                sources.push(
+                    source
+                        .parse()
+                        .unwrap_or_else(|_| panic!("invalid source {}", source)),
+                );
+            }
+            _ => panic!("unknown argument {}", arg),
+        }
+    }
+
+    if sources.is_empty() {
+        sources.push(Source::new((500, 0), Tile::Sand));
+    }
+
+    if verify {
+        verify_modes(&map, &sources, storage);
+        return;
     }
 
     map.print();
-    println!("{}", sand_count + 1);
+
+    let mut abyss_map = Map {
+        boundary: Boundary::Abyss,
+        ..map.clone()
+    };
+    abyss_map.set_storage(storage, &sources);
+    println!(
+        "{}",
+        count_resting_particles(&mut abyss_map, &sources, mode)
+    );
+
+    map.set_storage(storage, &sources);
+    println!("{}", count_resting_particles(&mut map, &sources, mode));
+    map.print();
 }
@@ -1,10 +1,72 @@
-#![feature(btree_drain_filter)]
-
 use std::{
-    collections::{BTreeSet, HashSet},
+    collections::HashSet,
     str::FromStr,
+    sync::atomic::{AtomicBool, AtomicI64, Ordering},
 };
 
+// Determines the shape of a sensor's range
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Metric {
+    // Diamond
+    Manhattan,
+    // Square
+    Chebyshev,
+    // Circle
+    Euclidean,
+}
+impl Metric {
+    // Euclidean distances are squared so they stay exact, only ever compared with each other
+    fn distance(self, dx: i64, dy: i64) -> i64 {
+        match self {
+            Metric::Manhattan => dx.abs() + dy.abs(),
+            Metric::Chebyshev => dx.abs().max(dy.abs()),
+            Metric::Euclidean => dx * dx + dy * dy,
+        }
+    }
+
+    // Unlike distance, not squared
+    fn real_distance(self, dx: f64, dy: f64) -> f64 {
+        match self {
+            Metric::Manhattan => dx.abs() + dy.abs(),
+            Metric::Chebyshev => dx.abs().max(dy.abs()),
+            Metric::Euclidean => dx.hypot(dy),
+        }
+    }
+
+    // Converts a value returned by distance into tiles
+    fn to_tiles(self, distance: i64) -> f64 {
+        match self {
+            Metric::Manhattan | Metric::Chebyshev => distance as f64,
+            Metric::Euclidean => (distance as f64).sqrt(),
+        }
+    }
+
+    // How far a range extends sideways dy rows from its center
+    fn half_width(self, range: i64, dy: i64) -> Option<i64> {
+        let dy = dy.abs();
+        match self {
+            Metric::Manhattan => Some(range - dy).filter(|&width| width >= 0),
+            Metric::Chebyshev => (dy <= range).then_some(range),
+            Metric::Euclidean => {
+                let remaining = range - dy * dy;
+                (remaining >= 0).then(|| integer_sqrt(remaining))
+            }
+        }
+    }
+}
+
+fn integer_sqrt(value: i64) -> i64 {
+    // the float estimate can be off by one for large values
+    let mut result = (value as f64).sqrt() as i64;
+    while result * result > value {
+        result -= 1;
+    }
+    while (result + 1) * (result + 1) <= value {
+        result += 1;
+    }
+    result
+}
+
 #[derive(Debug, Clone)]
 struct Sensor {
     x: i64,
@@ -13,22 +75,37 @@ struct Sensor {
     beacon_y: i64,
 }
 impl Sensor {
-    fn distance_to_beacon(&self) -> i64 {
-        (self.x - self.beacon_x).abs() + (self.y - self.beacon_y).abs()
+    fn distance_to_beacon(&self, metric: Metric) -> i64 {
+        metric.distance(self.x - self.beacon_x, self.y - self.beacon_y)
     }
 
-    fn visible_tiles_in_row(&self, y: i64) -> Option<(i64, i64)> {
-        let distance = self.distance_to_beacon();
-        let dy = (self.y - y).abs();
+    fn covers(&self, x: i64, y: i64, metric: Metric) -> bool {
+        metric.distance(self.x - x, self.y - y) <= self.distance_to_beacon(metric)
+    }
 
-        let remaining_distance_for_x = distance - dy;
-        if remaining_distance_for_x < 0 {
-            None
-        } else {
-            let min = self.x - remaining_distance_for_x;
-            let max = self.x + remaining_distance_for_x;
-            Some((min, max))
-        }
+    // Manhattan: x + y = c and x - y = c diagonals, Chebyshev: columns and rows either side
+    fn boundary_lines(&self, metric: Metric) -> Option<([i64; 2], [i64; 2])> {
+        let outside = self.distance_to_beacon(metric) + 1;
+        let (first, second) = match metric {
+            Metric::Manhattan => (self.x + self.y, self.x - self.y),
+            Metric::Chebyshev => (self.x, self.y),
+            Metric::Euclidean => return None,
+        };
+
+        Some((
+            [first - outside, first + outside],
+            [second - outside, second + outside],
+        ))
+    }
+
+    fn visible_tiles_in_row(&self, y: i64, metric: Metric) -> Option<(i64, i64)> {
+        let distance = self.distance_to_beacon(metric);
+        let dy = self.y - y;
+
+        let remaining_distance_for_x = metric.half_width(distance, dy)?;
+        let min = self.x - remaining_distance_for_x;
+        let max = self.x + remaining_distance_for_x;
+        Some((min, max))
     }
 }
 impl FromStr for Sensor {
@@ -82,11 +159,15 @@ impl FromStr for Sensor {
 
 #[derive(Debug, Default, Clone)]
 struct RangeGroup {
-    ranges: BTreeSet<(i64, i64)>,
+    ranges: Vec<(i64, i64)>,
 }
 impl RangeGroup {
+    fn clear(&mut self) {
+        self.ranges.clear();
+    }
+
     fn add_range(&mut self, min: i64, max: i64) {
-        self.ranges.insert((min, max));
+        self.ranges.push((min, max));
         // while self.simplify() {}
     }
 
@@ -100,7 +181,6 @@ impl RangeGroup {
     }
 
     fn find_first_uncovered(&self, min: i64, max: i64) -> Option<i64> {
-        dbg!(self);
         let mut test = min;
         loop {
             if test > max {
@@ -115,65 +195,81 @@ impl RangeGroup {
         }
     }
 
-    fn range_count(&self, min: i64, max: i64) -> usize {
-        let mut count = 0;
-
-        for (rmin, rmax) in &self.ranges {
-            if *rmax >= min && *rmin <= max {
-                count += 1;
-            } else if *rmin <= min && *rmax >= max {
-                count += 1;
-            }
+    fn len(&self) -> usize {
+        let mut len = 0;
+        for (start, end) in &self.ranges {
+            len += (end - start) + 1; // inclusive
         }
-
-        count
+        len as usize
     }
 
-    fn len(&self) -> usize {
+    // Assumes the group is simplified, so no ranges overlap
+    fn len_within(&self, min: i64, max: i64) -> usize {
         let mut len = 0;
         for (start, end) in &self.ranges {
-            len += (end - start) + 1; // inclusive
+            let start = (*start).max(min);
+            let end = (*end).min(max);
+            if start <= end {
+                len += (end - start) + 1; // inclusive
+            }
         }
         len as usize
     }
 
     fn simplify(&mut self) -> bool {
-        let mut changed = false;
-        let mut new_ranges = BTreeSet::new();
-        // For each first range, find everything that starts inside it. Remove those entries, then extend
-        // the first range to the maximum of everything that started inside.
-        while let Some(first_range) = self.ranges.pop_first() {
-            let mut new_max = first_range.1;
-            for entry in self.ranges.drain_filter(|(min, _)| first_range.1 >= *min) {
-                new_max = new_max.max(entry.1);
-                changed = true;
-            }
+        self.ranges.sort_unstable();
 
-            new_ranges.insert((first_range.0, new_max));
+        // Extend the last kept range with everything that starts inside it, otherwise keep the
+        // range as the start of a new one.
+        let mut kept = 0;
+        for i in 0..self.ranges.len() {
+            let (min, max) = self.ranges[i];
+            if kept > 0 && self.ranges[kept - 1].1 >= min {
+                self.ranges[kept - 1].1 = self.ranges[kept - 1].1.max(max);
+            } else {
+                self.ranges[kept] = (min, max);
+                kept += 1;
+            }
         }
 
-        self.ranges = new_ranges;
+        let changed = kept < self.ranges.len();
+        self.ranges.truncate(kept);
         changed
     }
 }
 
 struct Map {
     sensors: Vec<Sensor>,
+    metric: Metric,
 }
 impl Map {
     fn new(sensors: Vec<Sensor>) -> Self {
-        Self { sensors }
+        Self {
+            sensors,
+            metric: Metric::Manhattan,
+        }
     }
 
-    fn count_beaconless_tiles_in_row(&self, y: i64) -> usize {
+    fn coverage_in_row(&self, y: i64) -> RangeGroup {
         let mut seen_xs = RangeGroup::default();
+        self.fill_coverage_in_row(y, &mut seen_xs);
+        seen_xs
+    }
+
+    // Reuses an existing group
+    fn fill_coverage_in_row(&self, y: i64, seen_xs: &mut RangeGroup) {
+        seen_xs.clear();
 
         for sensor in &self.sensors {
-            if let Some((min, max)) = sensor.visible_tiles_in_row(y) {
+            if let Some((min, max)) = sensor.visible_tiles_in_row(y, self.metric) {
                 seen_xs.add_range(min, max);
             }
         }
         while seen_xs.simplify() {}
+    }
+
+    fn count_beaconless_tiles_in_row(&self, y: i64) -> usize {
+        let seen_xs = self.coverage_in_row(y);
 
         let mut beacon_xs = HashSet::new();
         for sensor in &self.sensors {
@@ -185,46 +281,538 @@ impl Map {
         seen_xs.len() - beacon_xs.len()
     }
 
-    fn get_hidden_beacon_x(&self, test_y: i64, min_x: i64, max_x: i64) -> Option<i64> {
-        let mut seen_xs = RangeGroup::default();
+    fn get_hidden_beacon_x(
+        &self,
+        test_y: i64,
+        min_x: i64,
+        max_x: i64,
+        seen_xs: &mut RangeGroup,
+    ) -> Option<i64> {
+        self.fill_coverage_in_row(test_y, seen_xs);
 
+        if seen_xs.len_within(min_x, max_x) < (max_x - min_x + 1) as usize {
+            seen_xs.find_first_uncovered(min_x, max_x)
+        } else {
+            None
+        }
+    }
+
+    // Corners are inclusive
+    fn count_covered_in_rect(
+        &self,
+        (min_x, min_y): (i64, i64),
+        (max_x, max_y): (i64, i64),
+    ) -> usize {
+        (min_y..=max_y)
+            .map(|y| self.coverage_in_row(y).len_within(min_x, max_x))
+            .sum()
+    }
+
+    // Going by how large the coordinates are
+    fn is_example(&self) -> bool {
+        let largest_coordinate = self
+            .sensors
+            .iter()
+            .flat_map(|sensor| [sensor.x, sensor.y, sensor.beacon_x, sensor.beacon_y])
+            .map(i64::abs)
+            .max()
+            .unwrap_or(0);
+
+        largest_coordinate < 1000
+    }
+
+    fn infer_query_row(&self) -> i64 {
+        if self.is_example() {
+            10
+        } else {
+            2_000_000
+        }
+    }
+
+    fn infer_max_coord(&self) -> i64 {
+        if self.is_example() {
+            20
+        } else {
+            4_000_000
+        }
+    }
+
+    fn is_covered(&self, x: i64, y: i64) -> bool {
+        self.sensors
+            .iter()
+            .any(|sensor| sensor.covers(x, y, self.metric))
+    }
+
+    // Tiles just outside several sensors' ranges, where boundary lines cross or meet an edge
+    fn isolated_uncovered_tiles(
+        &self,
+        (min_x, min_y): (i64, i64),
+        (max_x, max_y): (i64, i64),
+    ) -> Option<Vec<(i64, i64)>> {
+        let mut firsts = HashSet::new();
+        let mut seconds = HashSet::new();
         for sensor in &self.sensors {
-            if let Some((min, max)) = sensor.visible_tiles_in_row(test_y) {
-                seen_xs.add_range(min, max);
+            let (sensor_firsts, sensor_seconds) = sensor.boundary_lines(self.metric)?;
+            firsts.extend(sensor_firsts);
+            seconds.extend(sensor_seconds);
+        }
+
+        let metric = self.metric;
+        let intersections = firsts.iter().flat_map(|first| {
+            seconds.iter().filter_map(move |second| match metric {
+                // lines only cross on a tile if their constants have the same parity
+                Metric::Manhattan => ((first - second) % 2 == 0)
+                    .then_some(((first + second) / 2, (first - second) / 2)),
+                Metric::Chebyshev => Some((*first, *second)),
+                Metric::Euclidean => unreachable!(),
+            })
+        });
+        // a tile on an edge of the rectangle only needs lines of one family around it
+        let mut edge_crossings = Vec::new();
+        match metric {
+            Metric::Manhattan => {
+                for first in &firsts {
+                    edge_crossings.extend([
+                        (min_x, first - min_x),
+                        (max_x, first - max_x),
+                        (first - min_y, min_y),
+                        (first - max_y, max_y),
+                    ]);
+                }
+                for second in &seconds {
+                    edge_crossings.extend([
+                        (min_x, min_x - second),
+                        (max_x, max_x - second),
+                        (second + min_y, min_y),
+                        (second + max_y, max_y),
+                    ]);
+                }
+            }
+            Metric::Chebyshev => {
+                for &first in &firsts {
+                    edge_crossings.extend([(first, min_y), (first, max_y)]);
+                }
+                for &second in &seconds {
+                    edge_crossings.extend([(min_x, second), (max_x, second)]);
+                }
             }
+            Metric::Euclidean => unreachable!(),
         }
-        while seen_xs.simplify() {}
+        let corners = [
+            (min_x, min_y),
+            (min_x, max_y),
+            (max_x, min_y),
+            (max_x, max_y),
+        ];
 
-        if seen_xs.range_count(min_x, max_x) > 1 {
-            seen_xs.find_first_uncovered(min_x, max_x)
-        } else {
-            None
+        let mut result = corners
+            .into_iter()
+            .chain(intersections)
+            .chain(edge_crossings)
+            .filter(|&(x, y)| x >= min_x && x <= max_x && y >= min_y && y <= max_y)
+            .filter(|&(x, y)| !self.is_covered(x, y))
+            .collect::<Vec<_>>();
+        result.sort_unstable();
+        result.dedup();
+        Some(result)
+    }
+
+    // The only uncovered tile must be isolated, if the metric has boundary lines
+    fn find_hidden_beacon(&self, min: i64, max: i64, threads: usize) -> Option<(i64, i64)> {
+        match self.isolated_uncovered_tiles((min, min), (max, max)) {
+            Some(tiles) => tiles.first().copied(),
+            None => self.scan_for_hidden_beacon_parallel(min, max, threads),
+        }
+    }
+
+    fn scan_for_hidden_beacon(&self, min: i64, max: i64) -> Option<(i64, i64)> {
+        let mut seen_xs = RangeGroup::default();
+        for y in min..=max {
+            if let Some(x) = self.get_hidden_beacon_x(y, min, max, &mut seen_xs) {
+                println!("found tile: {}, {}", x, y);
+                return Some((x, y));
+            }
+        }
+
+        None
+    }
+
+    // Threads claim blocks of rows until any of them finds the beacon
+    fn scan_for_hidden_beacon_parallel(
+        &self,
+        min: i64,
+        max: i64,
+        threads: usize,
+    ) -> Option<(i64, i64)> {
+        const ROWS_PER_BLOCK: i64 = 1000;
+
+        let next_block = AtomicI64::new(min);
+        let found = AtomicBool::new(false);
+
+        std::thread::scope(|scope| {
+            let workers = (0..threads)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut seen_xs = RangeGroup::default();
+                        while !found.load(Ordering::Relaxed) {
+                            let start = next_block.fetch_add(ROWS_PER_BLOCK, Ordering::Relaxed);
+                            if start > max {
+                                return None;
+                            }
+
+                            for y in start..(start + ROWS_PER_BLOCK).min(max + 1) {
+                                if let Some(x) = self.get_hidden_beacon_x(y, min, max, &mut seen_xs)
+                                {
+                                    found.store(true, Ordering::Relaxed);
+                                    return Some((x, y));
+                                }
+                            }
+                        }
+
+                        None
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let result = workers
+                .into_iter()
+                .filter_map(|worker| worker.join().unwrap())
+                .min_by_key(|&(x, y)| (y, x));
+            if let Some((x, y)) = result {
+                println!("found tile: {}, {}", x, y);
+            }
+            result
+        })
+    }
+}
+
+// Maps tiles onto an image about IMAGE_SIZE pixels across
+struct Viewport {
+    min: (i64, i64),
+    max: (i64, i64),
+    tiles_per_pixel: f64,
+    width: usize,
+    height: usize,
+}
+impl Viewport {
+    const IMAGE_SIZE: f64 = 800.0;
+
+    fn new(min: (i64, i64), max: (i64, i64)) -> Self {
+        let tiles_wide = (max.0 - min.0 + 1) as f64;
+        let tiles_high = (max.1 - min.1 + 1) as f64;
+        let tiles_per_pixel = tiles_wide.max(tiles_high) / Self::IMAGE_SIZE;
+
+        Self {
+            min,
+            max,
+            tiles_per_pixel,
+            width: (tiles_wide / tiles_per_pixel).ceil() as usize,
+            height: (tiles_high / tiles_per_pixel).ceil() as usize,
+        }
+    }
+
+    // Middle of a tile in pixels
+    fn to_pixel(&self, x: i64, y: i64) -> (f64, f64) {
+        (
+            ((x - self.min.0) as f64 + 0.5) / self.tiles_per_pixel,
+            ((y - self.min.1) as f64 + 0.5) / self.tiles_per_pixel,
+        )
+    }
+
+    // Middle of a pixel in tiles
+    fn pixel_center(&self, px: usize, py: usize) -> (f64, f64) {
+        (
+            self.min.0 as f64 + (px as f64 + 0.5) * self.tiles_per_pixel - 0.5,
+            self.min.1 as f64 + (py as f64 + 0.5) * self.tiles_per_pixel - 0.5,
+        )
+    }
+
+    // Tiles covered by a pixel along one axis, always at least one
+    fn tiles_in_pixel(&self, pixel: usize, min: i64, max: i64) -> (i64, i64) {
+        let start = min + (pixel as f64 * self.tiles_per_pixel).floor() as i64;
+        let end = min + ((pixel + 1) as f64 * self.tiles_per_pixel).ceil() as i64 - 1;
+        (start.min(max), end.clamp(start, max))
+    }
+}
+
+// Shaded by how many sensors cover each pixel, red where a tile is uncovered
+fn render_ppm(map: &Map, viewport: &Viewport, path: &str) {
+    const UNCOVERED: [u8; 3] = [255, 0, 0];
+    const OUTLINE: [u8; 3] = [20, 20, 20];
+    const SENSOR: [u8; 3] = [0, 90, 255];
+    const BEACON: [u8; 3] = [255, 200, 0];
+
+    let mut pixels = vec![[255u8; 3]; viewport.width * viewport.height];
+
+    for py in 0..viewport.height {
+        let (y, _) = viewport.tiles_in_pixel(py, viewport.min.1, viewport.max.1);
+        let coverage = map.coverage_in_row(y);
+
+        for px in 0..viewport.width {
+            let (start_x, end_x) = viewport.tiles_in_pixel(px, viewport.min.0, viewport.max.0);
+            let (fx, fy) = viewport.pixel_center(px, py);
+
+            let pixel = &mut pixels[py * viewport.width + px];
+            if coverage.len_within(start_x, end_x) < (end_x - start_x + 1) as usize {
+                *pixel = UNCOVERED;
+                continue;
+            }
+
+            let mut count = 0;
+            for sensor in &map.sensors {
+                // the range covers whole tiles, so its edge is half a tile beyond the last one
+                let distance = map
+                    .metric
+                    .real_distance(sensor.x as f64 - fx, sensor.y as f64 - fy);
+                let edge = map.metric.to_tiles(sensor.distance_to_beacon(map.metric)) + 0.5;
+                if (distance - edge).abs() <= viewport.tiles_per_pixel {
+                    *pixel = OUTLINE;
+                    count = 0;
+                    break;
+                }
+                if distance < edge {
+                    count += 1;
+                }
+            }
+            if count > 0 {
+                let shade = 230u8.saturating_sub(25 * count.min(8) as u8);
+                *pixel = [shade, shade, shade];
+            }
         }
     }
+
+    // dots are drawn at least as big as a tile
+    let tile_size = (1.0 / viewport.tiles_per_pixel).ceil() as i64;
+    let mut draw_dot = |x: i64, y: i64, radius: i64, color: [u8; 3]| {
+        let radius = radius.max(tile_size / 2);
+        if x < viewport.min.0 || x > viewport.max.0 || y < viewport.min.1 || y > viewport.max.1 {
+            return;
+        }
+
+        let (px, py) = viewport.to_pixel(x, y);
+        for dy in -radius..=radius {
+            for dx in -radius..=radius {
+                let px = px as i64 + dx;
+                let py = py as i64 + dy;
+                if px >= 0
+                    && py >= 0
+                    && (px as usize) < viewport.width
+                    && (py as usize) < viewport.height
+                {
+                    pixels[py as usize * viewport.width + px as usize] = color;
+                }
+            }
+        }
+    };
+
+    for sensor in &map.sensors {
+        draw_dot(sensor.x, sensor.y, 2, SENSOR);
+        draw_dot(sensor.beacon_x, sensor.beacon_y, 2, BEACON);
+    }
+    // isolated gaps are smaller than a pixel at large scales, so make sure they stand out
+    let isolated = map.isolated_uncovered_tiles(viewport.min, viewport.max);
+    for (x, y) in isolated.unwrap_or_default() {
+        draw_dot(x, y, 3, UNCOVERED);
+    }
+
+    let mut data = format!("P6\n{} {}\n255\n", viewport.width, viewport.height).into_bytes();
+    data.extend(pixels.iter().flatten());
+    std::fs::write(path, data).unwrap();
+}
+
+// Draws each sensor's range joined to its beacon, uncovered tiles in red
+fn render_svg(map: &Map, viewport: &Viewport, path: &str) {
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n",
+        viewport.width, viewport.height
+    );
+    svg.push_str("<rect width=\"100%\" height=\"100%\" fill=\"white\"/>\n");
+
+    for sensor in &map.sensors {
+        let range = map.metric.to_tiles(sensor.distance_to_beacon(map.metric)) + 0.5;
+        let (sx, sy) = viewport.to_pixel(sensor.x, sensor.y);
+        let range = range / viewport.tiles_per_pixel;
+        let style = "fill=\"gray\" fill-opacity=\"0.25\" stroke=\"black\" stroke-width=\"1\"";
+        match map.metric {
+            Metric::Manhattan => {
+                let corners = [
+                    (sx, sy - range),
+                    (sx + range, sy),
+                    (sx, sy + range),
+                    (sx - range, sy),
+                ];
+                let points = corners
+                    .iter()
+                    .map(|(x, y)| format!("{:.2},{:.2}", x, y))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                svg.push_str(&format!("<polygon points=\"{}\" {}/>\n", points, style));
+            }
+            Metric::Chebyshev => svg.push_str(&format!(
+                "<rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\" {}/>\n",
+                sx - range,
+                sy - range,
+                range * 2.0,
+                range * 2.0,
+                style
+            )),
+            Metric::Euclidean => svg.push_str(&format!(
+                "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"{:.2}\" {}/>\n",
+                sx, sy, range, style
+            )),
+        }
+
+        let (bx, by) = viewport.to_pixel(sensor.beacon_x, sensor.beacon_y);
+        svg.push_str(&format!(
+            "<line x1=\"{:.2}\" y1=\"{:.2}\" x2=\"{:.2}\" y2=\"{:.2}\" stroke=\"blue\" stroke-dasharray=\"4\"/>\n",
+            sx, sy, bx, by
+        ));
+        svg.push_str(&format!(
+            "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"3\" fill=\"blue\"/>\n",
+            sx, sy
+        ));
+        svg.push_str(&format!(
+            "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"3\" fill=\"orange\"/>\n",
+            bx, by
+        ));
+    }
+
+    // every uncovered tile can be drawn when tiles are at least a pixel wide; otherwise only the
+    // isolated ones are, and they're drawn bigger than their actual size so they can be seen
+    let tile_size = 1.0 / viewport.tiles_per_pixel;
+    if tile_size >= 1.0 {
+        for y in viewport.min.1..=viewport.max.1 {
+            let coverage = map.coverage_in_row(y);
+            for x in viewport.min.0..=viewport.max.0 {
+                if coverage.next_skip_ahead_point(x).is_none() {
+                    let (px, py) = viewport.to_pixel(x, y);
+                    svg.push_str(&format!(
+                        "<rect x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\" fill=\"red\"/>\n",
+                        px - tile_size / 2.0,
+                        py - tile_size / 2.0,
+                        tile_size,
+                        tile_size
+                    ));
+                }
+            }
+        }
+    } else {
+        let isolated = map.isolated_uncovered_tiles(viewport.min, viewport.max);
+        for (x, y) in isolated.unwrap_or_default() {
+            let (px, py) = viewport.to_pixel(x, y);
+            svg.push_str(&format!(
+                "<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"4\" fill=\"red\"/>\n",
+                px, py
+            ));
+        }
+    }
+
+    svg.push_str("</svg>\n");
+    std::fs::write(path, svg).unwrap();
+}
+
+fn parse_list(s: &str) -> Vec<i64> {
+    s.split(',').map(|part| part.parse().unwrap()).collect()
 }
 
 fn main() {
-    let max_coord = 4_000_000;
     let input = std::fs::read_to_string("input.txt").unwrap();
 
     let sensors = input
         .lines()
         .map(|line| line.parse().unwrap())
         .collect::<Vec<_>>();
-    let map = Map::new(sensors);
+    let mut map = Map::new(sensors);
+
+    let mut query_row = None;
+    let mut max_coord = None;
+    let mut rows = Vec::new();
+    let mut rects = Vec::new();
+    let mut verify = false;
+    let mut threads = std::thread::available_parallelism().map_or(1, usize::from);
+    let mut render_path = None;
+    let mut render_rect = None;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--row" => query_row = Some(args.next().unwrap().parse().unwrap()),
+            "--max-coord" => max_coord = Some(args.next().unwrap().parse().unwrap()),
+            "--rows" => rows.extend(parse_list(&args.next().unwrap())),
+            "--rect" => {
+                let &[min_x, min_y, max_x, max_y] = &parse_list(&args.next().unwrap())[..] else {
+                    panic!("--rect takes min_x,min_y,max_x,max_y");
+                };
+                rects.push(((min_x, min_y), (max_x, max_y)));
+            }
+            "--verify" => verify = true,
+            "--metric" => {
+                map.metric = match args.next().unwrap().as_str() {
+                    "manhattan" => Metric::Manhattan,
+                    "chebyshev" => Metric::Chebyshev,
+                    "euclidean" => Metric::Euclidean,
+                    other => panic!("unknown metric {}", other),
+                }
+            }
+            "--threads" => threads = args.next().unwrap().parse().unwrap(),
+            "--render" => render_path = Some(args.next().unwrap()),
+            "--render-rect" => {
+                let &[min_x, min_y, max_x, max_y] = &parse_list(&args.next().unwrap())[..] else {
+                    panic!("--render-rect takes min_x,min_y,max_x,max_y");
+                };
+                render_rect = Some(((min_x, min_y), (max_x, max_y)));
+            }
+            _ => panic!("unknown argument {}", arg),
+        }
+    }
 
-    println!("{}", map.count_beaconless_tiles_in_row(2_000_000));
+    let query_row = query_row.unwrap_or_else(|| map.infer_query_row());
+    let max_coord = max_coord.unwrap_or_else(|| map.infer_max_coord());
 
-    let mut beacon_x = 0;
-    let mut beacon_y = 0;
-    for y in 0..max_coord {
-        if let Some(x) = map.get_hidden_beacon_x(y, 0, max_coord) {
-            beacon_x = x;
-            beacon_y = y;
-            println!("found tile: {}, {}", x, y);
-            break;
+    if let Some(path) = render_path {
+        let (min, max) = render_rect.unwrap_or(((0, 0), (max_coord, max_coord)));
+        let viewport = Viewport::new(min, max);
+        if path.ends_with(".svg") {
+            render_svg(&map, &viewport, &path);
+        } else if path.ends_with(".ppm") {
+            render_ppm(&map, &viewport, &path);
+        } else {
+            panic!("unknown image format for {}", path);
         }
     }
 
-    println!("{}", beacon_x * max_coord + beacon_y);
+    for y in rows {
+        println!("row {}: {}", y, map.count_beaconless_tiles_in_row(y));
+    }
+    for (min, max) in rects {
+        let covered = map.count_covered_in_rect(min, max);
+        let area = ((max.0 - min.0 + 1) * (max.1 - min.1 + 1)) as usize;
+        println!(
+            "{:?} to {:?}: {} covered, {} uncovered",
+            min,
+            max,
+            covered,
+            area - covered
+        );
+    }
+
+    println!("{}", map.count_beaconless_tiles_in_row(query_row));
+
+    let hidden_beacon = map.find_hidden_beacon(0, max_coord, threads);
+    match hidden_beacon {
+        Some((beacon_x, beacon_y)) => {
+            println!("hidden beacon: {}, {}", beacon_x, beacon_y);
+            println!("{}", beacon_x * 4_000_000 + beacon_y);
+        }
+        None => println!("no hidden beacon"),
+    }
+    if verify {
+        let scanned = if threads > 1 {
+            map.scan_for_hidden_beacon_parallel(0, max_coord, threads)
+        } else {
+            map.scan_for_hidden_beacon(0, max_coord)
+        };
+        assert_eq!(scanned, hidden_beacon, "row scan disagrees");
+        println!("row scan agrees");
+    }
 }
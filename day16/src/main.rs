@@ -1,40 +1,96 @@
 use std::{
     collections::{HashMap, VecDeque},
-    fmt::Debug,
+    fmt::{Debug, Display},
     hash::Hash,
+    rc::Rc,
+    sync::atomic::{AtomicI64, AtomicUsize, Ordering},
+    thread,
     time::Instant,
 };
 
 #[derive(Hash, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
 struct ValveId(usize);
-impl ValveId {
-    const MAX: usize = 702;
-
-    fn from_chars(chars: &[u8]) -> Self {
-        ValveId((((chars[0] - b'A') as u16) * 26 + (chars[1] - b'A') as u16).into())
+impl Debug for ValveId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Valve #{}", self.0))
     }
 }
-impl Debug for ValveId {
+
+#[derive(Debug)]
+enum InputError {
+    TooManyValves,
+    Malformed(String),
+}
+impl Display for InputError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let b0 = (self.0 / 26) as u8 + b'A';
-        let b1 = (self.0 % 26) as u8 + b'A';
-        let bytes = [b0, b1];
-        let text = std::str::from_utf8(&bytes).unwrap();
-        f.write_fmt(format_args!("Valve {}", text))
+        match self {
+            InputError::TooManyValves => f.write_fmt(format_args!(
+                "more than {} valves aren't supported",
+                ValveSet::CAPACITY
+            )),
+            InputError::Malformed(line) => f.write_fmt(format_args!("malformed line: {}", line)),
+        }
     }
 }
 
-const NULL_VALVE_ID: ValveId = ValveId(0xFFFF);
+// Dense ids in the order names are first seen
+#[derive(Debug, Default)]
+struct ValveNames {
+    names: Vec<String>,
+    ids: HashMap<String, ValveId>,
+}
+impl ValveNames {
+    fn intern(&mut self, name: &str) -> Result<ValveId, InputError> {
+        if let Some(id) = self.ids.get(name) {
+            return Ok(*id);
+        }
+        if self.names.len() == ValveSet::CAPACITY {
+            return Err(InputError::TooManyValves);
+        }
 
-fn build_valve_mask_mapping(mut valves: Vec<ValveId>) -> Box<[i64; ValveId::MAX]> {
-    let mut result = Box::new([0; ValveId::MAX]);
+        let id = ValveId(self.names.len());
+        self.names.push(name.to_string());
+        self.ids.insert(name.to_string(), id);
+        Ok(id)
+    }
 
-    valves.sort();
-    for (idx, valve_id) in valves.iter().enumerate() {
-        result[valve_id.0] = 1 << idx;
+    fn get(&self, name: &str) -> Option<ValveId> {
+        self.ids.get(name).copied()
     }
 
-    result
+    fn name(&self, id: ValveId) -> &str {
+        &self.names[id.0]
+    }
+
+    fn len(&self) -> usize {
+        self.names.len()
+    }
+}
+
+// One bit per valve id
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct ValveSet {
+    words: [u64; ValveSet::WORDS],
+}
+impl ValveSet {
+    const WORDS: usize = 4;
+    const CAPACITY: usize = Self::WORDS * 64;
+
+    fn contains(&self, id: ValveId) -> bool {
+        self.words[id.0 / 64] & (1 << (id.0 % 64)) != 0
+    }
+
+    fn insert(&mut self, id: ValveId) {
+        self.words[id.0 / 64] |= 1 << (id.0 % 64);
+    }
+
+    fn remove(&mut self, id: ValveId) {
+        self.words[id.0 / 64] &= !(1 << (id.0 % 64));
+    }
+
+    fn is_empty(&self) -> bool {
+        self.words.iter().all(|word| *word == 0)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -43,37 +99,45 @@ struct Valve {
     adjacent_valves: Vec<ValveId>,
 }
 impl Valve {
-    fn parse(str: &str) -> (ValveId, Self) {
+    fn parse(str: &str, names: &mut ValveNames) -> Result<(ValveId, Self), InputError> {
+        let malformed = || InputError::Malformed(str.to_string());
+
         let mut words = str.split_ascii_whitespace();
-        let valve_id = ValveId::from_chars(words.nth(1).unwrap().as_bytes());
+        let valve_id = names.intern(words.nth(1).ok_or_else(malformed)?)?;
         let flow_rate = words
             .nth(2)
-            .unwrap()
-            .split("=")
-            .nth(1)
-            .unwrap()
-            .strip_suffix(";")
-            .unwrap()
-            .parse()
-            .unwrap();
+            .and_then(|word| word.strip_prefix("rate="))
+            .and_then(|word| word.strip_suffix(';'))
+            .and_then(|word| word.parse().ok())
+            .ok_or_else(malformed)?;
         let adjacent_valves = words
             .skip(4)
-            .map(|word| ValveId::from_chars(&word.as_bytes()[..2]))
-            .collect();
+            .map(|word| names.intern(word.trim_end_matches(',')))
+            .collect::<Result<_, _>>()?;
 
-        (
+        Ok((
             valve_id,
             Valve {
                 flow_rate,
                 adjacent_valves,
             },
-        )
+        ))
     }
 }
 
+fn parse_valves(input: &str) -> Result<(ValveNames, HashMap<ValveId, Valve>), InputError> {
+    let mut names = ValveNames::default();
+    let valves = input
+        .lines()
+        .map(|line| Valve::parse(line, &mut names))
+        .collect::<Result<_, _>>()?;
+
+    Ok((names, valves))
+}
+
 // Floyd-Warshall Algorithm
-fn all_pairs_shortest_paths(valves: &HashMap<ValveId, Valve>) -> Vec<[i64; ValveId::MAX]> {
-    let mut result = vec![[i64::MAX; ValveId::MAX]; ValveId::MAX];
+fn all_pairs_shortest_paths(valves: &HashMap<ValveId, Valve>, valve_count: usize) -> Vec<Vec<i64>> {
+    let mut result = vec![vec![i64::MAX; valve_count]; valve_count];
     let keys = valves.keys().copied().collect::<Vec<_>>();
 
     for v1 in &keys {
@@ -99,50 +163,255 @@ fn all_pairs_shortest_paths(valves: &HashMap<ValveId, Valve>) -> Vec<[i64; Valve
     result
 }
 
-fn combinations<const SIZE: usize>(
-    ids: &[ValveId],
-    active_agents: usize,
-    dest: &mut Vec<[ValveId; SIZE]>,
+// Only the start and valuable valves, by compact id so the matrix stays small
+#[derive(Debug, PartialEq, Eq)]
+struct Distances {
+    // Compact id of each valve, if it's relevant
+    compact_ids: Vec<Option<usize>>,
+    size: usize,
+    matrix: Vec<i64>,
+}
+impl Distances {
+    fn relevant_valves(valves: &HashMap<ValveId, Valve>, start: ValveId) -> Vec<ValveId> {
+        let mut result = valves
+            .iter()
+            .filter(|(id, valve)| **id == start || valve.flow_rate > 0)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        if !result.contains(&start) {
+            result.push(start);
+        }
+        result.sort();
+        result
+    }
+
+    fn empty(valve_count: usize, relevant: &[ValveId]) -> Self {
+        let mut compact_ids = vec![None; valve_count];
+        for (idx, valve_id) in relevant.iter().enumerate() {
+            compact_ids[valve_id.0] = Some(idx);
+        }
+
+        Self {
+            compact_ids,
+            size: relevant.len(),
+            matrix: vec![i64::MAX; relevant.len() * relevant.len()],
+        }
+    }
+
+    // BFS from each relevant valve
+    fn with_bfs(
+        valves: &HashMap<ValveId, Valve>,
+        valve_count: usize,
+        relevant: &[ValveId],
+    ) -> Self {
+        let mut result = Self::empty(valve_count, relevant);
+        let mut distances = vec![i64::MAX; valve_count];
+        let mut queue = VecDeque::new();
+
+        for (from_idx, from) in relevant.iter().enumerate() {
+            distances.fill(i64::MAX);
+            distances[from.0] = 0;
+            queue.push_back(*from);
+
+            while let Some(current) = queue.pop_front() {
+                let Some(valve) = valves.get(&current) else {
+                    continue;
+                };
+                for next in &valve.adjacent_valves {
+                    if distances[next.0] == i64::MAX {
+                        distances[next.0] = distances[current.0] + 1;
+                        queue.push_back(*next);
+                    }
+                }
+            }
+
+            for (to_idx, to) in relevant.iter().enumerate() {
+                result.matrix[from_idx * result.size + to_idx] = distances[to.0];
+            }
+        }
+
+        result
+    }
+
+    // Floyd-Warshall over every valve, then drop what doesn't matter
+    fn with_floyd_warshall(
+        valves: &HashMap<ValveId, Valve>,
+        valve_count: usize,
+        relevant: &[ValveId],
+    ) -> Self {
+        let mut result = Self::empty(valve_count, relevant);
+        let all_pairs = all_pairs_shortest_paths(valves, valve_count);
+
+        for (from_idx, from) in relevant.iter().enumerate() {
+            for (to_idx, to) in relevant.iter().enumerate() {
+                result.matrix[from_idx * result.size + to_idx] = all_pairs[from.0][to.0];
+            }
+        }
+
+        result
+    }
+
+    fn get(&self, from: ValveId, to: ValveId) -> i64 {
+        let compact_id = |valve_id: ValveId| {
+            self.compact_ids[valve_id.0]
+                .unwrap_or_else(|| panic!("{:?} isn't relevant to any route", valve_id))
+        };
+        self.matrix[compact_id(from) * self.size + compact_id(to)]
+    }
+}
+
+// Runs each way of working out the distances `repeats` times, checking they agree
+fn benchmark_distances(
+    valves: &HashMap<ValveId, Valve>,
+    valve_count: usize,
+    start: ValveId,
+    repeats: u32,
 ) {
-    dest.clear();
+    let relevant = Distances::relevant_valves(valves, start);
 
-    fn helper<const SIZE: usize>(
-        ids: &[ValveId],
-        mut tmp: [ValveId; SIZE],
-        max_non_null: usize,
-        idx: usize,
-        i: usize,
-        dest: &mut Vec<[ValveId; SIZE]>,
-    ) {
-        if idx == max_non_null {
-            dest.push(tmp.clone());
-        } else if i < ids.len() {
-            tmp[idx] = ids[i];
-            helper(ids, tmp, max_non_null, idx + 1, i + 1, dest);
-            helper(ids, tmp, max_non_null, idx, i + 1, dest);
+    let before_bfs = Instant::now();
+    let mut bfs = None;
+    for _ in 0..repeats {
+        bfs = Some(Distances::with_bfs(valves, valve_count, &relevant));
+    }
+    let bfs_time = before_bfs.elapsed() / repeats;
+
+    let before_floyd_warshall = Instant::now();
+    let mut floyd_warshall = None;
+    for _ in 0..repeats {
+        floyd_warshall = Some(Distances::with_floyd_warshall(
+            valves,
+            valve_count,
+            &relevant,
+        ));
+    }
+    let floyd_warshall_time = before_floyd_warshall.elapsed() / repeats;
+
+    assert_eq!(bfs, floyd_warshall, "distances disagree");
+    println!(
+        "{} valves, {} relevant: bfs {:?}, floyd-warshall {:?}",
+        valve_count,
+        relevant.len(),
+        bfs_time,
+        floyd_warshall_time
+    );
+}
+
+#[derive(Debug)]
+enum GraphIssue {
+    UnknownTunnelTarget { from: ValveId, to: ValveId },
+    UnreachableValve(ValveId),
+    AsymmetricTunnel { from: ValveId, to: ValveId },
+}
+impl GraphIssue {
+    // Whether the solvers can still cope with the graph. One-way tunnels leave some valves
+    // i64::MAX apart, which the solvers treat as never getting there.
+    fn is_fatal(&self) -> bool {
+        !matches!(self, GraphIssue::AsymmetricTunnel { .. })
+    }
+
+    fn describe(&self, names: &ValveNames) -> String {
+        match *self {
+            GraphIssue::UnknownTunnelTarget { from, to } => format!(
+                "valve {} has a tunnel to {}, which doesn't exist",
+                names.name(from),
+                names.name(to)
+            ),
+            GraphIssue::UnreachableValve(valve_id) => format!(
+                "valve {} has a flow rate but can't be reached",
+                names.name(valve_id)
+            ),
+            GraphIssue::AsymmetricTunnel { from, to } => format!(
+                "valve {} has a tunnel to {}, but not the other way round",
+                names.name(from),
+                names.name(to)
+            ),
         }
     }
+}
 
-    helper(ids, [NULL_VALVE_ID; SIZE], active_agents, 0, 0, dest);
+fn validate_graph(
+    valves: &HashMap<ValveId, Valve>,
+    shortest_paths: &Distances,
+    start: ValveId,
+) -> Vec<GraphIssue> {
+    let mut ids = valves.keys().copied().collect::<Vec<_>>();
+    ids.sort();
+
+    let mut result = Vec::new();
+    for from in &ids {
+        for to in &valves[from].adjacent_valves {
+            match valves.get(to) {
+                None => result.push(GraphIssue::UnknownTunnelTarget {
+                    from: *from,
+                    to: *to,
+                }),
+                Some(valve) if !valve.adjacent_valves.contains(from) => {
+                    result.push(GraphIssue::AsymmetricTunnel {
+                        from: *from,
+                        to: *to,
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    for valve_id in &ids {
+        if valves[valve_id].flow_rate > 0 && shortest_paths.get(start, *valve_id) == i64::MAX {
+            result.push(GraphIssue::UnreachableValve(*valve_id));
+        }
+    }
+
+    result
 }
 
-fn permutations<const SIZE: usize>(mut ids: [ValveId; SIZE], dest: &mut Vec<[ValveId; SIZE]>) {
+// Each chunk of size ids in dest is one combination, size must be at least 1
+fn combinations<T: Copy>(ids: &[T], size: usize, dest: &mut Vec<T>) {
+    // The last chunk of `dest` is the combination being built, it's copied each time it's complete
+    fn helper<T: Copy>(ids: &[T], size: usize, idx: usize, i: usize, dest: &mut Vec<T>) {
+        if idx == size {
+            dest.extend_from_within(dest.len() - size..);
+        } else {
+            for j in i..ids.len() {
+                let start = dest.len() - size;
+                dest[start + idx] = ids[j];
+                helper(ids, size, idx + 1, j + 1, dest);
+            }
+        }
+    }
+
     dest.clear();
-    let mut stack = [0; SIZE];
+    if size > ids.len() {
+        return;
+    }
+    dest.resize(size, ids[0]);
+    helper(ids, size, 0, 0, dest);
+    dest.truncate(dest.len() - size);
+}
 
-    dest.push(ids.clone());
+// Each chunk of ids.len() ids in dest is one permutation
+fn permutations(ids: &[ValveId], dest: &mut Vec<ValveId>) {
+    dest.clear();
+    dest.extend_from_slice(ids);
 
+    // Heap's algorithm, each permutation is a copy of the previous one with one swap applied
+    let size = ids.len();
+    if size < 2 {
+        return;
+    }
+    let mut stack = vec![0; size];
     let mut i = 1;
-    while i < SIZE {
+    while i < size {
         if stack[i] < i {
+            let start = dest.len();
+            dest.extend_from_within(start - size..start);
             if i % 2 == 0 {
-                ids.swap(0, i);
+                dest.swap(start, start + i);
             } else {
-                ids.swap(stack[i], i);
+                dest.swap(start + stack[i], start + i);
             }
 
-            dest.push(ids.clone());
-
             stack[i] += 1;
             i = 1;
         } else {
@@ -155,17 +424,28 @@ fn permutations<const SIZE: usize>(mut ids: [ValveId; SIZE], dest: &mut Vec<[Val
 #[derive(Debug, Clone, Copy)]
 enum AgentState {
     Travelling {
+        origin: ValveId,
         destination: ValveId,
         reactivates_on: i64, // started - path_len - 1 (time taken to open valve)
     },
     Active(ValveId),
+    // Stopped opening valves, leaving the rest to the other agents
+    Retired,
 }
 
+const RETIRE: ValveId = ValveId(usize::MAX);
+
 #[derive(Debug, Clone)]
-struct AgentStates<const AGENTS: usize> {
-    agents: [AgentState; AGENTS],
+struct AgentStates {
+    agents: Vec<AgentState>,
 }
-impl<const AGENTS: usize> AgentStates<AGENTS> {
+impl AgentStates {
+    fn new(count: usize, start: ValveId) -> Self {
+        Self {
+            agents: vec![AgentState::Active(start); count],
+        }
+    }
+
     fn active_count(&self) -> usize {
         self.agents
             .iter()
@@ -201,18 +481,28 @@ impl<const AGENTS: usize> AgentStates<AGENTS> {
         destination: ValveId,
         path_length: i64,
     ) {
+        let AgentState::Active(origin) = self.agents[agent_idx] else {
+            panic!("agent {} is already travelling", agent_idx);
+        };
+
         self.agents[agent_idx] = AgentState::Travelling {
+            origin,
             destination,
-            reactivates_on: current_time - path_length - 1, // -1 for the time to open the valve
+            // -1 for the time to open the valve, unreachable valves have a path length of i64::MAX
+            reactivates_on: current_time.saturating_sub(path_length).saturating_sub(1),
         };
     }
 
+    fn retire(&mut self, agent_idx: usize) {
+        self.agents[agent_idx] = AgentState::Retired;
+    }
+
     // Returns (amount of time waited, amount of pressure released)
     fn wait_until_any_active(
         &mut self,
         current_time_remaining: i64,
         valves: &HashMap<ValveId, Valve>,
-        // history: &mut Vec<String>,
+        history: &mut Option<Rc<History>>,
     ) -> (i64, i64) {
         let time_to_wait = self
             .agents
@@ -236,19 +526,29 @@ impl<const AGENTS: usize> AgentStates<AGENTS> {
 
         // Mark any agents that got there on that timestamp active
         let mut pressure_released = 0;
-        for idx in 0..AGENTS {
+        for idx in 0..self.agents.len() {
             if let AgentState::Travelling {
+                origin,
                 destination,
                 reactivates_on,
             } = self.agents[idx]
             {
                 if new_time_remaining - reactivates_on <= 0 {
-                    // history.push(format!(
-                    //     "Agent {} opens {:?} with {} minutes left",
-                    //     idx, destination, new_time_remaining
-                    // ));
-                    pressure_released +=
-                        new_time_remaining * valves.get(&destination).unwrap().flow_rate;
+                    let released = new_time_remaining * valves.get(&destination).unwrap().flow_rate;
+                    pressure_released += released;
+
+                    if released > 0 {
+                        *history = Some(Rc::new(History {
+                            step: PlanStep {
+                                agent: idx,
+                                origin,
+                                valve: destination,
+                                time_remaining: new_time_remaining,
+                                pressure_released: released,
+                            },
+                            previous: history.take(),
+                        }));
+                    }
 
                     self.agents[idx] = AgentState::Active(destination);
                 }
@@ -258,74 +558,250 @@ impl<const AGENTS: usize> AgentStates<AGENTS> {
         (time_to_wait, pressure_released)
     }
 }
-impl<const AGENTS: usize> Default for AgentStates<AGENTS> {
-    fn default() -> Self {
+
+#[derive(Debug, Clone)]
+struct PlanStep {
+    agent: usize,
+    origin: ValveId,
+    valve: ValveId,
+    time_remaining: i64,
+    pressure_released: i64,
+}
+
+// Linked list from the most recent valve back, so states can share it
+#[derive(Debug)]
+struct History {
+    step: PlanStep,
+    previous: Option<Rc<History>>,
+}
+
+#[derive(Debug)]
+struct Plan {
+    total_pressure_released: i64,
+    steps: Vec<PlanStep>,
+    stats: SearchStats,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct SearchStats {
+    explored: usize,
+    pruned: usize,
+}
+
+// Optimistic: every hop is the shortest one and the biggest valves open first
+struct UpperBound {
+    // Valuable valves, biggest flow rate first
+    valuable: Vec<(ValveId, i64)>,
+    shortest_hop: i64,
+}
+impl UpperBound {
+    fn new(valves: &HashMap<ValveId, Valve>, shortest_paths: &Distances) -> Self {
+        let mut valuable = valves
+            .iter()
+            .filter(|(_, valve)| valve.flow_rate > 0)
+            .map(|(id, valve)| (*id, valve.flow_rate))
+            .collect::<Vec<_>>();
+        valuable.sort_by_key(|(id, flow_rate)| (-flow_rate, *id));
+
+        let mut shortest_hop = i64::MAX;
+        for (from, _) in &valuable {
+            for (to, _) in &valuable {
+                if from != to {
+                    shortest_hop = shortest_hop.min(shortest_paths.get(*from, *to));
+                }
+            }
+        }
+
         Self {
-            agents: [AgentState::Active(ValveId::from_chars(&[b'A', b'A'])); AGENTS],
+            valuable,
+            shortest_hop,
         }
     }
+
+    fn estimate(
+        &self,
+        state: &VolcanoState,
+        shortest_paths: &Distances,
+        opening_times: &mut Vec<i64>,
+    ) -> i64 {
+        let closed = state.remaining_valuable_unqueued_closed_valves;
+        let mut result = state.total_pressure_released;
+
+        // (valve the agent is at or heading to, time remaining when it's free to move on)
+        opening_times.clear();
+        for agent in &state.agents.agents {
+            let (position, free_at) = match *agent {
+                AgentState::Active(valve_id) => (valve_id, state.remaining_time),
+                AgentState::Travelling {
+                    destination,
+                    reactivates_on,
+                    ..
+                } => {
+                    result += reactivates_on.max(0) * self.flow_rate(destination);
+                    (destination, reactivates_on)
+                }
+                AgentState::Retired => continue,
+            };
+
+            let first_hop = self
+                .valuable
+                .iter()
+                .filter(|(id, _)| closed.contains(*id))
+                .map(|(id, _)| shortest_paths.get(position, *id))
+                .filter(|&distance| distance != i64::MAX)
+                .min();
+            let Some(first_hop) = first_hop else {
+                continue;
+            };
+
+            // With fewer than two valuable valves there's no shortest hop, only the first opening
+            // Agents heading somewhere they can't reach are free hugely far in the past
+            let mut time_remaining = free_at.saturating_sub(first_hop).saturating_sub(1);
+            while time_remaining > 0 {
+                opening_times.push(time_remaining);
+                time_remaining = time_remaining.saturating_sub(self.shortest_hop.saturating_add(1));
+            }
+        }
+        opening_times.sort_unstable_by(|a, b| b.cmp(a));
+
+        let closed_flow_rates = self
+            .valuable
+            .iter()
+            .filter(|(id, _)| closed.contains(*id))
+            .map(|(_, flow_rate)| flow_rate);
+        for (flow_rate, time_remaining) in closed_flow_rates.zip(opening_times.iter()) {
+            result += flow_rate * time_remaining;
+        }
+
+        result
+    }
+
+    fn flow_rate(&self, valve_id: ValveId) -> i64 {
+        self.valuable
+            .iter()
+            .find(|(id, _)| *id == valve_id)
+            .map_or(0, |(_, flow_rate)| *flow_rate)
+    }
 }
 
 #[derive(Debug)]
-struct VolcanoState<const AGENTS: usize> {
+struct VolcanoState {
     total_pressure_released: i64,
     remaining_time: i64,
-    agents: AgentStates<AGENTS>,
-    remaining_valuable_unqueued_closed_valves: i64,
-    // history: Vec<String>,
+    agents: AgentStates,
+    remaining_valuable_unqueued_closed_valves: ValveSet,
+    history: Option<Rc<History>>,
 }
 
-fn do_the_solve<const AGENTS: usize>(
-    valves: &HashMap<ValveId, Valve>,
-    shortest_paths: &Vec<[i64; ValveId::MAX]>,
-    masks: &Box<[i64; ValveId::MAX]>,
-    time_allowed: i64,
-) {
-    let mut queue = VecDeque::with_capacity(1_000);
-    queue.push_back(VolcanoState::<AGENTS> {
-        total_pressure_released: 0,
-        remaining_time: time_allowed,
-        agents: Default::default(),
-        remaining_valuable_unqueued_closed_valves: {
-            let mut result = 0;
-            for (valve_id, valve) in valves.iter() {
-                if valve.flow_rate != 0 {
-                    result |= masks[valve_id.0];
-                }
-            }
-            result
-        },
-        // history: Vec::new(),
-    });
+// Depth first over which valve each agent heads to next
+struct Search<'a> {
+    valves: &'a HashMap<ValveId, Valve>,
+    shortest_paths: &'a Distances,
+    upper_bound: UpperBound,
+    prune: bool,
+    // Best pressure released by anyone searching, possibly on other threads
+    best: &'a AtomicI64,
+    stats: SearchStats,
 
-    let mut combination_buffer = Vec::with_capacity(100);
-    let mut permutation_buffer = Vec::with_capacity(100);
-    let mut valve_id_buffer = Vec::with_capacity(100);
+    // Best found by this search
+    total_pressure_released: i64,
+    history: Option<Rc<History>>,
 
-    let mut total_pressure_released = 0;
-    // let mut history = Vec::new();
+    combination_buffer: Vec<ValveId>,
+    permutation_buffer: Vec<ValveId>,
+    agent_buffer: Vec<usize>,
+    agent_combination_buffer: Vec<usize>,
+    destination_buffer: Vec<ValveId>,
+    valve_id_buffer: Vec<ValveId>,
+    opening_times: Vec<i64>,
+}
+impl<'a> Search<'a> {
+    fn new(
+        valves: &'a HashMap<ValveId, Valve>,
+        shortest_paths: &'a Distances,
+        prune: bool,
+        best: &'a AtomicI64,
+    ) -> Self {
+        Self {
+            valves,
+            shortest_paths,
+            upper_bound: UpperBound::new(valves, shortest_paths),
+            prune,
+            best,
+            stats: SearchStats::default(),
+            total_pressure_released: 0,
+            history: None,
+            combination_buffer: Vec::with_capacity(100),
+            permutation_buffer: Vec::with_capacity(100),
+            agent_buffer: Vec::with_capacity(10),
+            agent_combination_buffer: Vec::with_capacity(100),
+            destination_buffer: Vec::with_capacity(10),
+            valve_id_buffer: Vec::with_capacity(100),
+            opening_times: Vec::with_capacity(100),
+        }
+    }
 
-    while let Some(state) = queue.pop_back() {
+    fn initial_state(&self, start: ValveId, agent_count: usize, time_allowed: i64) -> VolcanoState {
+        VolcanoState {
+            total_pressure_released: 0,
+            remaining_time: time_allowed,
+            agents: AgentStates::new(agent_count, start),
+            remaining_valuable_unqueued_closed_valves: {
+                let mut result = ValveSet::default();
+                for (valve_id, valve) in self.valves.iter() {
+                    if valve.flow_rate != 0 {
+                        result.insert(*valve_id);
+                    }
+                }
+                result
+            },
+            history: None,
+        }
+    }
+
+    fn run(&mut self, queue: &mut VecDeque<VolcanoState>) {
+        while let Some(state) = queue.pop_back() {
+            self.step(state, queue);
+        }
+    }
+
+    // Records `state` if it's the best so far, and queues up everything that can follow it
+    fn step(&mut self, state: VolcanoState, queue: &mut VecDeque<VolcanoState>) {
         if state.remaining_time <= 0 {
-            continue;
+            return;
         }
 
-        if state.total_pressure_released > total_pressure_released {
-            total_pressure_released = state.total_pressure_released;
-            // history = state.history.clone();
+        if state.total_pressure_released > self.total_pressure_released {
+            self.total_pressure_released = state.total_pressure_released;
+            self.history = state.history.clone();
+            self.best
+                .fetch_max(state.total_pressure_released, Ordering::Relaxed);
         }
 
+        // Other threads may have found something better, so prune against the best of everyone
+        if self.prune
+            && self
+                .upper_bound
+                .estimate(&state, self.shortest_paths, &mut self.opening_times)
+                <= self.best.load(Ordering::Relaxed)
+        {
+            self.stats.pruned += 1;
+            return;
+        }
+        self.stats.explored += 1;
+
         let active_agents = state.agents.active_count();
 
         // Edge case: All valves are assigned, but some agents are still travelling to their assigned valve
-        if state.remaining_valuable_unqueued_closed_valves == 0 && state.agents.inactive_count() > 0
+        if state.remaining_valuable_unqueued_closed_valves.is_empty()
+            && state.agents.inactive_count() > 0
         {
             let mut new_agent_states = state.agents.clone();
-            // let mut new_history = state.history.clone();
+            let mut new_history = state.history.clone();
             let (time_to_wait, pressure_released) = new_agent_states.wait_until_any_active(
                 state.remaining_time,
-                valves,
-                // &mut new_history,
+                self.valves,
+                &mut new_history,
             );
             let remaining_time = state.remaining_time - time_to_wait;
 
@@ -334,72 +810,669 @@ fn do_the_solve<const AGENTS: usize>(
                 remaining_time,
                 agents: new_agent_states,
                 remaining_valuable_unqueued_closed_valves: state
-                    .remaining_valuable_unqueued_closed_valves
-                    .clone(),
-                // history: new_history,
+                    .remaining_valuable_unqueued_closed_valves,
+                history: new_history,
             });
         }
 
-        valve_id_buffer.clear();
-        for valve_id in valves.keys() {
-            if state.remaining_valuable_unqueued_closed_valves & masks[valve_id.0] != 0 {
-                valve_id_buffer.push(*valve_id);
+        self.valve_id_buffer.clear();
+        for valve_id in self.valves.keys() {
+            if state
+                .remaining_valuable_unqueued_closed_valves
+                .contains(*valve_id)
+            {
+                self.valve_id_buffer.push(*valve_id);
             }
         }
-        combinations::<AGENTS>(&valve_id_buffer[..], active_agents, &mut combination_buffer);
-
-        for next_valve_ids in &combination_buffer {
-            permutations::<AGENTS>(next_valve_ids.clone(), &mut permutation_buffer);
-            'permutations: for agent_permutation in &permutation_buffer {
-                let agent_destinations = state.agents.active_agents().zip(agent_permutation);
-
-                let mut new_agent_states = state.agents.clone();
-                let mut new_closed_valves = state.remaining_valuable_unqueued_closed_valves.clone();
-                for ((agent_idx, agent_cur_valve), dest_valve) in agent_destinations {
-                    // If any agent was assigned a null ID, try a different permutation. This can happen if there are
-                    // less active agents than agents; in this case, the combination assigns a number of null destinations
-                    // equal to the number of inactive agents.
-                    if *dest_valve == NULL_VALVE_ID {
-                        continue 'permutations;
+        if active_agents == 0 || self.valve_id_buffer.is_empty() {
+            return;
+        }
+        // Agents can retire instead, it may be better to leave a valve to someone else. Everyone
+        // active can retire as long as someone is still travelling to a valve.
+        let fewest_opening = if state.agents.inactive_count() > 0 {
+            0
+        } else {
+            1
+        };
+        let most_opening = active_agents.min(self.valve_id_buffer.len());
+
+        // Taken out of self so branches can be pushed while looping over them
+        let mut valve_combinations = std::mem::take(&mut self.combination_buffer);
+        let mut valve_permutations = std::mem::take(&mut self.permutation_buffer);
+        let mut agents = std::mem::take(&mut self.agent_buffer);
+        let mut agent_combinations = std::mem::take(&mut self.agent_combination_buffer);
+        let mut destinations = std::mem::take(&mut self.destination_buffer);
+        agents.clear();
+        agents.extend(0..active_agents);
+
+        for opening in fewest_opening..=most_opening {
+            if opening == 0 {
+                destinations.clear();
+                destinations.resize(active_agents, RETIRE);
+                self.push_branch(&state, &destinations, queue);
+                continue;
+            }
+
+            // Pick which valves get opened, which agents open them, then who opens which
+            combinations(&self.valve_id_buffer, opening, &mut valve_combinations);
+            combinations(&agents, opening, &mut agent_combinations);
+            for next_valve_ids in valve_combinations.chunks_exact(opening) {
+                permutations(next_valve_ids, &mut valve_permutations);
+                for opening_agents in agent_combinations.chunks_exact(opening) {
+                    for valve_permutation in valve_permutations.chunks_exact(opening) {
+                        destinations.clear();
+                        destinations.resize(active_agents, RETIRE);
+                        for (agent, valve_id) in opening_agents.iter().zip(valve_permutation) {
+                            destinations[*agent] = *valve_id;
+                        }
+                        self.push_branch(&state, &destinations, queue);
                     }
+                }
+            }
+        }
 
-                    let path_length = shortest_paths[agent_cur_valve.0][dest_valve.0];
+        self.combination_buffer = valve_combinations;
+        self.permutation_buffer = valve_permutations;
+        self.agent_buffer = agents;
+        self.agent_combination_buffer = agent_combinations;
+        self.destination_buffer = destinations;
+    }
+
+    // Queues the state after each active agent sets off for its destination, in order, or retires
+    fn push_branch(
+        &self,
+        state: &VolcanoState,
+        destinations: &[ValveId],
+        queue: &mut VecDeque<VolcanoState>,
+    ) {
+        let agent_destinations = state.agents.active_agents().zip(destinations);
+
+        let mut new_agent_states = state.agents.clone();
+        let mut new_closed_valves = state.remaining_valuable_unqueued_closed_valves;
+        for ((agent_idx, agent_cur_valve), dest_valve) in agent_destinations {
+            if *dest_valve == RETIRE {
+                new_agent_states.retire(agent_idx);
+                continue;
+            }
+
+            let path_length = self.shortest_paths.get(agent_cur_valve, *dest_valve);
+
+            new_agent_states.start_travelling(
+                agent_idx,
+                state.remaining_time,
+                *dest_valve,
+                path_length,
+            );
+
+            new_closed_valves.remove(*dest_valve);
+        }
+
+        let mut new_history = state.history.clone();
+        let (time_to_wait, pressure_released) = new_agent_states.wait_until_any_active(
+            state.remaining_time,
+            self.valves,
+            &mut new_history,
+        );
+        let remaining_time = state.remaining_time - time_to_wait;
+        queue.push_back(VolcanoState {
+            total_pressure_released: state.total_pressure_released + pressure_released,
+            remaining_time,
+            agents: new_agent_states,
+            remaining_valuable_unqueued_closed_valves: new_closed_valves,
+            history: new_history,
+        });
+    }
+
+    fn into_plan(self) -> Plan {
+        let mut steps = Vec::new();
+        let mut node = self.history.as_deref();
+        while let Some(History { step, previous }) = node {
+            steps.push(step.clone());
+            node = previous.as_deref();
+        }
+        steps.reverse();
+
+        Plan {
+            total_pressure_released: self.total_pressure_released,
+            steps,
+            stats: self.stats,
+        }
+    }
+}
+
+fn do_the_solve(
+    valves: &HashMap<ValveId, Valve>,
+    shortest_paths: &Distances,
+    start: ValveId,
+    agent_count: usize,
+    time_allowed: i64,
+    prune: bool,
+) -> Plan {
+    let best = AtomicI64::new(0);
+    let mut search = Search::new(valves, shortest_paths, prune, &best);
+
+    let mut queue = VecDeque::with_capacity(1_000);
+    queue.push_back(search.initial_state(start, agent_count, time_allowed));
+    search.run(&mut queue);
+
+    search.into_plan()
+}
+
+// Threads take branches off the initial state and share the best so far for pruning
+fn do_the_solve_parallel(
+    valves: &HashMap<ValveId, Valve>,
+    shortest_paths: &Distances,
+    start: ValveId,
+    agent_count: usize,
+    time_allowed: i64,
+    prune: bool,
+    threads: usize,
+) -> Plan {
+    let best = AtomicI64::new(0);
+    let next_branch = AtomicUsize::new(0);
 
-                    new_agent_states.start_travelling(
-                        agent_idx,
-                        state.remaining_time,
-                        *dest_valve,
-                        path_length,
+    let plans = thread::scope(|scope| {
+        let workers = (0..threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    // Every thread expands the initial state itself, so the branches come out in
+                    // the same order everywhere and no state needs to cross threads
+                    let mut search = Search::new(valves, shortest_paths, prune, &best);
+                    let mut branches = VecDeque::new();
+                    search.step(
+                        search.initial_state(start, agent_count, time_allowed),
+                        &mut branches,
                     );
+                    let mut branches = branches.into_iter().map(Some).collect::<Vec<_>>();
+                    search.stats = SearchStats::default();
+
+                    let mut queue = VecDeque::with_capacity(1_000);
+                    loop {
+                        let idx = next_branch.fetch_add(1, Ordering::Relaxed);
+                        let Some(branch) = branches.get_mut(idx) else {
+                            break;
+                        };
+                        queue.push_back(branch.take().unwrap());
+                        search.run(&mut queue);
+                    }
+
+                    search.into_plan()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        workers
+            .into_iter()
+            .map(|worker| worker.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let mut stats = SearchStats {
+        // The initial state, which every thread explored
+        explored: 1,
+        pruned: 0,
+    };
+    for plan in &plans {
+        stats.explored += plan.stats.explored;
+        stats.pruned += plan.stats.pruned;
+    }
+
+    let mut plan = plans
+        .into_iter()
+        .max_by_key(|plan| plan.total_pressure_released)
+        .unwrap();
+    plan.stats = stats;
+    plan
+}
+
+// Best pressure for one agent per subset of valuable valves, bit i is valuable[i]
+struct SubsetTable {
+    valuable: Vec<ValveId>,
+    best: Vec<i64>,
+}
+impl SubsetTable {
+    // Anything bigger than this doesn't fit in memory anyway
+    const MAX_VALUABLE: usize = 24;
+
+    fn build(
+        valves: &HashMap<ValveId, Valve>,
+        shortest_paths: &Distances,
+        start: ValveId,
+        time_allowed: i64,
+    ) -> Self {
+        let mut valuable = valves
+            .iter()
+            .filter_map(|(id, valve)| (valve.flow_rate > 0).then_some(*id))
+            .collect::<Vec<_>>();
+        valuable.sort();
+        assert!(
+            valuable.len() <= Self::MAX_VALUABLE,
+            "the bitmask solver supports at most {} valuable valves, got {}",
+            Self::MAX_VALUABLE,
+            valuable.len()
+        );
+
+        let mut table = Self {
+            best: vec![0; 1 << valuable.len()],
+            valuable,
+        };
+        table.fill(valves, shortest_paths, start, time_allowed, 0, 0);
 
-                    new_closed_valves &= !masks[dest_valve.0];
+        // Opening fewer valves is always allowed, so each subset is at least as good as its subsets
+        for bit in 0..table.valuable.len() {
+            for subset in 0..table.best.len() {
+                if subset & (1 << bit) != 0 {
+                    table.best[subset] = table.best[subset].max(table.best[subset ^ (1 << bit)]);
                 }
+            }
+        }
 
-                // let mut new_history = state.history.clone();
-                let (time_to_wait, pressure_released) = new_agent_states.wait_until_any_active(
-                    state.remaining_time,
-                    valves,
-                    // &mut new_history,
-                );
-                let remaining_time = state.remaining_time - time_to_wait;
-                queue.push_back(VolcanoState {
-                    total_pressure_released: state.total_pressure_released + pressure_released,
-                    remaining_time,
-                    agents: new_agent_states,
-                    remaining_valuable_unqueued_closed_valves: new_closed_valves,
-                    // history: new_history,
-                });
+        table
+    }
+
+    fn fill(
+        &mut self,
+        valves: &HashMap<ValveId, Valve>,
+        shortest_paths: &Distances,
+        current: ValveId,
+        time_remaining: i64,
+        opened: usize,
+        pressure_released: i64,
+    ) {
+        self.best[opened] = self.best[opened].max(pressure_released);
+
+        for idx in 0..self.valuable.len() {
+            if opened & (1 << idx) != 0 {
+                continue;
             }
+
+            let next = self.valuable[idx];
+            let time_remaining = time_remaining
+                .saturating_sub(shortest_paths.get(current, next))
+                .saturating_sub(1);
+            if time_remaining <= 0 {
+                continue;
+            }
+
+            self.fill(
+                valves,
+                shortest_paths,
+                next,
+                time_remaining,
+                opened | (1 << idx),
+                pressure_released + time_remaining * valves[&next].flow_rate,
+            );
+        }
+    }
+
+    fn all(&self) -> usize {
+        self.best.len() - 1
+    }
+
+    // Returns (best pressure, subset given to the new agent) when splitting `subset` between one
+    // new agent and the agents already accounted for in `previous`
+    fn split(&self, previous: &[i64], subset: usize) -> (i64, usize) {
+        let mut result = (i64::MIN, 0);
+        let mut own = subset;
+        loop {
+            let pressure_released = self.best[own] + previous[subset ^ own];
+            if pressure_released > result.0 {
+                result = (pressure_released, own);
+            }
+
+            if own == 0 {
+                break;
+            }
+            own = (own - 1) & subset;
+        }
+
+        result
+    }
+
+    // One disjoint subset per agent
+    fn best_assignment(&self, agent_count: usize) -> (i64, Vec<usize>) {
+        if agent_count == 0 {
+            return (0, Vec::new());
+        }
+
+        // choices[n][subset] is what the (n + 2)th agent takes when `subset` is shared between
+        // n + 2 agents
+        let mut choices = Vec::new();
+        let mut previous = self.best.clone();
+        for _ in 2..agent_count {
+            let (combined, choice): (Vec<_>, Vec<_>) = (0..self.best.len())
+                .map(|subset| self.split(&previous, subset))
+                .unzip();
+            previous = combined;
+            choices.push(choice);
+        }
+
+        let mut subset = self.all();
+        if agent_count == 1 {
+            return (self.best[subset], vec![subset]);
+        }
+
+        let (total_pressure_released, last) = self.split(&previous, subset);
+        let mut assignment = vec![last];
+        subset ^= last;
+        for choice in choices.iter().rev() {
+            assignment.push(choice[subset]);
+            subset ^= choice[subset];
         }
+        assignment.push(subset);
+        assignment.reverse();
+
+        (total_pressure_released, assignment)
     }
 
-    // for history in history {
-    //     println!("{}", history);
-    // }
-    println!("{}", total_pressure_released);
+    // Finds the order in which an agent should open the valves in `subset`, returning (pressure,
+    // steps)
+    #[allow(clippy::too_many_arguments)]
+    fn best_route(
+        &self,
+        valves: &HashMap<ValveId, Valve>,
+        shortest_paths: &Distances,
+        agent: usize,
+        current: ValveId,
+        time_remaining: i64,
+        subset: usize,
+        steps: &mut Vec<PlanStep>,
+    ) -> (i64, Vec<PlanStep>) {
+        let mut result = (0, steps.clone());
+
+        for idx in 0..self.valuable.len() {
+            if subset & (1 << idx) == 0 {
+                continue;
+            }
+
+            let next = self.valuable[idx];
+            let time_remaining = time_remaining
+                .saturating_sub(shortest_paths.get(current, next))
+                .saturating_sub(1);
+            if time_remaining <= 0 {
+                continue;
+            }
+
+            let released = time_remaining * valves[&next].flow_rate;
+            steps.push(PlanStep {
+                agent,
+                origin: current,
+                valve: next,
+                time_remaining,
+                pressure_released: released,
+            });
+            let (pressure_released, route) = self.best_route(
+                valves,
+                shortest_paths,
+                agent,
+                next,
+                time_remaining,
+                subset ^ (1 << idx),
+                steps,
+            );
+            steps.pop();
+
+            if released + pressure_released > result.0 {
+                result = (released + pressure_released, route);
+            }
+        }
+
+        result
+    }
+}
+
+// Tabulate the best for every subset of valves, then share them out between the agents
+fn solve_with_subsets(
+    valves: &HashMap<ValveId, Valve>,
+    shortest_paths: &Distances,
+    start: ValveId,
+    agent_count: usize,
+    time_allowed: i64,
+) -> Plan {
+    let table = SubsetTable::build(valves, shortest_paths, start, time_allowed);
+    let (total_pressure_released, assignment) = table.best_assignment(agent_count);
+
+    let mut steps = Vec::new();
+    for (agent, subset) in assignment.into_iter().enumerate() {
+        let (_, route) = table.best_route(
+            valves,
+            shortest_paths,
+            agent,
+            start,
+            time_allowed,
+            subset,
+            &mut Vec::new(),
+        );
+        steps.extend(route);
+    }
+
+    Plan {
+        total_pressure_released,
+        steps,
+        stats: SearchStats::default(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Solver {
+    Search { prune: bool, threads: usize },
+    Subsets,
+}
+
+fn solve(
+    solver: Solver,
+    valves: &HashMap<ValveId, Valve>,
+    shortest_paths: &Distances,
+    start: ValveId,
+    agent_count: usize,
+    time_allowed: i64,
+) -> Plan {
+    match solver {
+        Solver::Search { prune, threads: 1 } => do_the_solve(
+            valves,
+            shortest_paths,
+            start,
+            agent_count,
+            time_allowed,
+            prune,
+        ),
+        Solver::Search { prune, threads } => do_the_solve_parallel(
+            valves,
+            shortest_paths,
+            start,
+            agent_count,
+            time_allowed,
+            prune,
+            threads,
+        ),
+        Solver::Subsets => {
+            solve_with_subsets(valves, shortest_paths, start, agent_count, time_allowed)
+        }
+    }
+}
+
+// Includes both ends
+fn shortest_path(valves: &HashMap<ValveId, Valve>, from: ValveId, to: ValveId) -> Vec<ValveId> {
+    let mut previous = HashMap::from([(from, from)]);
+    let mut queue = VecDeque::from([from]);
+    while let Some(current) = queue.pop_front() {
+        if current == to {
+            break;
+        }
+        for next in &valves[&current].adjacent_valves {
+            if !previous.contains_key(next) {
+                previous.insert(*next, current);
+                queue.push_back(*next);
+            }
+        }
+    }
+
+    let mut result = vec![to];
+    let mut current = to;
+    while current != from {
+        current = previous[&current];
+        result.push(current);
+    }
+    result.reverse();
+
+    result
 }
 
-// fn find_best_path(valves: &HashMap<ValveId, Valve>, shortest_paths: &Vec<[i64; ValveId::MAX]>) {
+fn print_plan(
+    plan: &Plan,
+    names: &ValveNames,
+    valves: &HashMap<ValveId, Valve>,
+    time_allowed: i64,
+) {
+    println!("{}", plan.total_pressure_released);
+
+    let mut steps = plan.steps.clone();
+    steps.sort_by_key(|step| (-step.time_remaining, step.agent));
+
+    let mut cumulative_pressure = 0;
+    for step in steps {
+        cumulative_pressure += step.pressure_released;
+        let path = shortest_path(valves, step.origin, step.valve)
+            .iter()
+            .map(|valve_id| names.name(*valve_id))
+            .collect::<Vec<_>>()
+            .join(" -> ");
+        println!(
+            "  minute {:2}: agent {} walks {} and opens {}, releasing {} (total {})",
+            time_allowed - step.time_remaining,
+            step.agent,
+            path,
+            names.name(step.valve),
+            step.pressure_released,
+            cumulative_pressure
+        );
+    }
+}
+
+// One colour per agent for highlighting routes, reused if there are more agents
+const ROUTE_COLOURS: [&str; 4] = ["red", "blue", "darkgreen", "orange"];
+
+// The graph is undirected, so label edges with the shorter way round
+fn compressed_distance(shortest_paths: &Distances, a: ValveId, b: ValveId) -> i64 {
+    shortest_paths.get(a, b).min(shortest_paths.get(b, a))
+}
+
+// Compressed only draws the start and valuable valves, edges labelled with distances
+fn write_dot(
+    path: &str,
+    names: &ValveNames,
+    valves: &HashMap<ValveId, Valve>,
+    shortest_paths: &Distances,
+    start: ValveId,
+    plan: &Plan,
+    compressed: bool,
+) {
+    let mut ids = valves
+        .iter()
+        .filter(|(id, valve)| !compressed || **id == start || valve.flow_rate > 0)
+        .map(|(id, _)| *id)
+        .collect::<Vec<_>>();
+    ids.sort();
+
+    // Which agents walk along each edge, and when each valve is opened
+    let mut route_edges: HashMap<(ValveId, ValveId), Vec<usize>> = HashMap::new();
+    let mut opened = HashMap::new();
+    for step in &plan.steps {
+        let hops = if compressed {
+            vec![step.origin, step.valve]
+        } else {
+            shortest_path(valves, step.origin, step.valve)
+        };
+        for hop in hops.windows(2) {
+            let edge = (hop[0].min(hop[1]), hop[0].max(hop[1]));
+            let agents = route_edges.entry(edge).or_default();
+            if !agents.contains(&step.agent) {
+                agents.push(step.agent);
+            }
+        }
+        opened.insert(step.valve, step);
+    }
+
+    let mut dot = String::from("graph valves {\n");
+    dot.push_str("  node [shape=circle, fontname=\"monospace\"];\n");
+
+    for id in &ids {
+        let valve = &valves[id];
+        let mut label = format!("{}\\nflow {}", names.name(*id), valve.flow_rate);
+        let mut style = String::new();
+        if let Some(step) = opened.get(id) {
+            label.push_str(&format!("\\n{} min left", step.time_remaining));
+            style.push_str(&format!(
+                ", color={}, penwidth=3",
+                ROUTE_COLOURS[step.agent % ROUTE_COLOURS.len()]
+            ));
+        }
+        if valve.flow_rate > 0 {
+            style.push_str(", style=filled, fillcolor=lightyellow");
+        }
+        if *id == start {
+            style.push_str(", shape=doublecircle");
+        }
+        dot.push_str(&format!(
+            "  \"{}\" [label=\"{}\"{}];\n",
+            names.name(*id),
+            label,
+            style
+        ));
+    }
+
+    for (idx, from) in ids.iter().enumerate() {
+        let neighbours = if compressed {
+            // One-way tunnels can leave valves unreachable from each other
+            ids[idx + 1..]
+                .iter()
+                .copied()
+                .filter(|to| compressed_distance(shortest_paths, *from, *to) != i64::MAX)
+                .collect()
+        } else {
+            let mut neighbours = valves[from]
+                .adjacent_valves
+                .iter()
+                .copied()
+                .filter(|to| to > from || !valves[to].adjacent_valves.contains(from))
+                .collect::<Vec<_>>();
+            neighbours.sort();
+            neighbours
+        };
+
+        for to in neighbours {
+            let mut style = Vec::new();
+            if compressed {
+                let distance = compressed_distance(shortest_paths, *from, to);
+                style.push(format!("label=\"{}\"", distance));
+            }
+            if let Some(agents) = route_edges.get(&(*from.min(&to), *from.max(&to))) {
+                let colours = agents
+                    .iter()
+                    .map(|agent| ROUTE_COLOURS[agent % ROUTE_COLOURS.len()])
+                    .collect::<Vec<_>>();
+                style.push(format!("color=\"{}\", penwidth=3", colours.join(":")));
+            } else if compressed {
+                style.push("color=gray".to_string());
+            }
+
+            dot.push_str(&format!(
+                "  \"{}\" -- \"{}\"",
+                names.name(*from),
+                names.name(to)
+            ));
+            if !style.is_empty() {
+                dot.push_str(&format!(" [{}]", style.join(", ")));
+            }
+            dot.push_str(";\n");
+        }
+    }
+
+    dot.push_str("}\n");
+    std::fs::write(path, dot).unwrap();
+}
+
+// fn find_best_path(valves: &HashMap<ValveId, Valve>, shortest_paths: &Distances) {
 //     let all_valuable_nodes: Vec<_> = valves
 //         .iter()
 //         .filter_map(|(id, valve)| if valve.flow_rate > 0 { Some(id) } else { None })
@@ -407,25 +1480,197 @@ fn do_the_solve<const AGENTS: usize>(
 // }
 
 fn main() {
+    // (agents, minutes) for each scenario to solve
+    let mut scenarios = Vec::new();
+    let mut solver = Solver::Search {
+        prune: true,
+        threads: 1,
+    };
+    let mut threads = 1;
+    let mut verify = false;
+    let mut start_name = "AA".to_string();
+    let mut dot_path = None;
+    let mut dot_compressed = false;
+    let mut bench_distances = false;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--scenario" => {
+                let scenario = args.next().unwrap();
+                let (agents, minutes) = scenario
+                    .split_once(':')
+                    .unwrap_or_else(|| panic!("scenario {} is not AGENTS:MINUTES", scenario));
+                let agents = agents.parse().unwrap();
+                assert!(agents > 0, "scenario {} needs at least one agent", scenario);
+                scenarios.push((agents, minutes.parse().unwrap()));
+            }
+            "--solver" => {
+                solver = match args.next().unwrap().as_str() {
+                    "search" => Solver::Search {
+                        prune: true,
+                        threads: 1,
+                    },
+                    "search-unpruned" => Solver::Search {
+                        prune: false,
+                        threads: 1,
+                    },
+                    "subsets" => Solver::Subsets,
+                    other => panic!("unknown solver {}", other),
+                }
+            }
+            "--verify" => verify = true,
+            "--threads" => threads = args.next().unwrap().parse().unwrap(),
+            "--start" => start_name = args.next().unwrap(),
+            "--dot" => dot_path = Some(args.next().unwrap()),
+            "--dot-compressed" => dot_compressed = true,
+            "--bench-distances" => bench_distances = true,
+            _ => panic!("unknown argument {}", arg),
+        }
+    }
+    if scenarios.is_empty() {
+        scenarios = vec![(1, 30), (2, 26), (3, 22)];
+    }
+    assert!(threads > 0, "at least one thread is needed");
+    if let Solver::Search {
+        threads: search_threads,
+        ..
+    } = &mut solver
+    {
+        *search_threads = threads;
+    }
+
     let start_loading = Instant::now();
     let input = std::fs::read_to_string("input.txt").unwrap();
-    let valves: HashMap<_, _> = input.lines().map(|line| Valve::parse(line)).collect();
-    let masks = build_valve_mask_mapping(valves.keys().copied().collect());
+    let (names, valves) =
+        parse_valves(&input).unwrap_or_else(|err| panic!("invalid input: {}", err));
+    let start_valve = names
+        .get(&start_name)
+        .unwrap_or_else(|| panic!("there's no valve {} to start from", start_name));
 
-    let shortest_paths = all_pairs_shortest_paths(&valves);
+    if bench_distances {
+        benchmark_distances(&valves, names.len(), start_valve, 100);
+        return;
+    }
+    let shortest_paths = Distances::with_bfs(
+        &valves,
+        names.len(),
+        &Distances::relevant_valves(&valves, start_valve),
+    );
+
+    let issues = validate_graph(&valves, &shortest_paths, start_valve);
+    for issue in &issues {
+        let severity = if issue.is_fatal() { "error" } else { "warning" };
+        eprintln!("{}: {}", severity, issue.describe(&names));
+    }
+    assert!(
+        !issues.iter().any(GraphIssue::is_fatal),
+        "the valve graph can't be solved"
+    );
     // dbg!(&shortest_paths);
+    let start_solving = Instant::now();
+
+    if verify {
+        for &(agents, minutes) in &scenarios {
+            let [unpruned, search, parallel, subsets] = [
+                Solver::Search {
+                    prune: false,
+                    threads: 1,
+                },
+                Solver::Search {
+                    prune: true,
+                    threads: 1,
+                },
+                // Always check splitting the work up, even if only one thread was asked for
+                Solver::Search {
+                    prune: true,
+                    threads: threads.max(2),
+                },
+                Solver::Subsets,
+            ]
+            .map(|solver| {
+                solve(
+                    solver,
+                    &valves,
+                    &shortest_paths,
+                    start_valve,
+                    agents,
+                    minutes,
+                )
+            });
+            for (solver, plan) in [
+                ("pruned search", &search),
+                ("parallel search", &parallel),
+                ("subsets", &subsets),
+            ] {
+                assert_eq!(
+                    unpruned.total_pressure_released, plan.total_pressure_released,
+                    "{} disagrees for {} agent(s) with {} minutes",
+                    solver, agents, minutes
+                );
+            }
+            let released = subsets
+                .steps
+                .iter()
+                .map(|step| step.pressure_released)
+                .sum();
+            assert_eq!(
+                subsets.total_pressure_released, released,
+                "subsets plan doesn't add up for {} agent(s) with {} minutes",
+                agents, minutes
+            );
+            println!(
+                "{} agent(s) with {} minutes: all solvers agree on {}",
+                agents, minutes, unpruned.total_pressure_released
+            );
+        }
+        return;
+    }
 
-    let start_1 = Instant::now();
-    do_the_solve::<1>(&valves, &shortest_paths, &masks, 30);
-    let start_2 = Instant::now();
-    do_the_solve::<2>(&valves, &shortest_paths, &masks, 26);
-    let start_3 = Instant::now();
-    do_the_solve::<3>(&valves, &shortest_paths, &masks, 22);
-    let finish = Instant::now();
+    let mut timings = Vec::new();
+    let mut plans = Vec::new();
+    for &(agents, minutes) in &scenarios {
+        let start = Instant::now();
+        let plan = solve(
+            solver,
+            &valves,
+            &shortest_paths,
+            start_valve,
+            agents,
+            minutes,
+        );
+        timings.push((start.elapsed(), plan.stats));
+        print_plan(&plan, &names, &valves, minutes);
+        plans.push(plan);
+    }
+
+    // Only the first scenario's route is drawn
+    if let Some(path) = dot_path {
+        write_dot(
+            &path,
+            &names,
+            &valves,
+            &shortest_paths,
+            start_valve,
+            &plans[0],
+            dot_compressed,
+        );
+    }
 
     println!("\n");
-    println!("Loaded and pre-processed in {:?}", start_1 - start_loading);
-    println!("Part 1 solved in {:?}", start_2 - start_1);
-    println!("Part 2 solved in {:?}", start_3 - start_2);
-    println!("Part 3 solved in {:?}", finish - start_3);
+    println!(
+        "Loaded and pre-processed in {:?}",
+        start_solving - start_loading
+    );
+    for ((agents, minutes), (timing, stats)) in scenarios.iter().zip(timings) {
+        println!(
+            "{} agent(s) with {} minutes solved in {:?}",
+            agents, minutes, timing
+        );
+        if let Solver::Search { .. } = solver {
+            println!(
+                "  explored {} states, pruned {}",
+                stats.explored, stats.pruned
+            );
+        }
+    }
 }